@@ -0,0 +1,295 @@
+use crate::{
+    configuration::{AppConfig, ChannelRef, STATE_DIRECTORY},
+    template,
+};
+use std::{collections::HashMap, path::Path};
+
+pub const STARTUP_PLACEHOLDERS: &[&str] = &["version", "host", "previous_uptime", "config_summary"];
+pub const SHUTDOWN_PLACEHOLDERS: &[&str] = &["version", "host", "uptime", "config_summary"];
+
+/// Find everything wrong with the configuration instead of stopping at the first problem
+pub fn check_configuration(config: &AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    let home = &config.home;
+    let aliases = &home.channel_aliases;
+
+    if config.discord.token.is_empty() {
+        match &config.discord.token_file {
+            None => problems.push(
+                "discord.token is empty and no token_file or credential was found".to_owned(),
+            ),
+            Some(token_file) if token_file.is_file() => {
+                problems.push(format!("discord.token_file {token_file:?} is empty"))
+            }
+            // failing to read it was already reported
+            Some(_) => (),
+        }
+    }
+
+    check_channel_id(
+        &mut problems,
+        "home.notification_discord_channel",
+        home.notification_discord_channel,
+    );
+    check_channel_id(&mut problems, "home.spam_channel_id", home.spam_channel_id);
+    for (alias, channel_id) in aliases {
+        check_channel_id(
+            &mut problems,
+            &format!("home.channel_aliases.{alias}"),
+            *channel_id,
+        );
+    }
+    if let Some(channel) = &home.startup_announcement.channel {
+        check_channel(
+            &mut problems,
+            "home.startup_announcement.channel",
+            channel,
+            aliases,
+        );
+    }
+    if let Some(channel) = &home.shutdown_announcement.channel {
+        check_channel(
+            &mut problems,
+            "home.shutdown_announcement.channel",
+            channel,
+            aliases,
+        );
+    }
+    let routes = &config.mqtt.outbound_routes;
+    for (name, route) in [
+        ("say_channel", &routes.say_channel),
+        ("send_file_channel", &routes.send_file_channel),
+        ("show_typing_channel", &routes.show_typing_channel),
+    ] {
+        for channel in route.allowed_channels.iter().flatten() {
+            check_channel(
+                &mut problems,
+                &format!("mqtt.outbound_routes.{name}.allowed_channels"),
+                channel,
+                aliases,
+            );
+        }
+    }
+    let home_assistant = &config.mqtt.home_assistant;
+    if let Some(channel) = &home_assistant.notify_channel {
+        check_channel(
+            &mut problems,
+            "mqtt.home_assistant.notify_channel",
+            channel,
+            aliases,
+        );
+    }
+    for quick_message in &home_assistant.quick_messages {
+        check_channel(
+            &mut problems,
+            &format!("mqtt.home_assistant.quick_messages.{}", quick_message.name),
+            &quick_message.channel,
+            aliases,
+        );
+    }
+
+    check_topic_name(&mut problems, "mqtt.base_route", &config.mqtt.base_route);
+    check_topic_name(
+        &mut problems,
+        "mqtt.home_assistant.discovery_prefix",
+        &home_assistant.discovery_prefix,
+    );
+    for subscription in &config.mqtt.subscriptions {
+        if let Err(e) = check_topic_filter(&subscription.topic) {
+            problems.push(format!("mqtt.subscriptions topic {e}"));
+        }
+    }
+
+    if let Err(e) = template::validate(&home.startup_announcement.template, STARTUP_PLACEHOLDERS) {
+        problems.push(format!("home.startup_announcement.template has {e}"));
+    }
+    if let Err(e) = template::validate(&home.shutdown_announcement.template, SHUTDOWN_PLACEHOLDERS)
+    {
+        problems.push(format!("home.shutdown_announcement.template has {e}"));
+    }
+
+    let tls = &config.mqtt.tls;
+    if tls.enabled {
+        match &tls.ca_file {
+            Some(ca_file) => check_file(&mut problems, "mqtt.tls.ca_file", ca_file),
+            None => problems.push("mqtt.tls.ca_file is required when tls is enabled".to_owned()),
+        }
+        match (&tls.client_certificate_file, &tls.client_key_file) {
+            (Some(certificate_file), Some(key_file)) => {
+                check_file(
+                    &mut problems,
+                    "mqtt.tls.client_certificate_file",
+                    certificate_file,
+                );
+                check_file(&mut problems, "mqtt.tls.client_key_file", key_file);
+            }
+            (None, None) => (),
+            _ => problems
+                .push("mqtt.tls needs both client_certificate_file and client_key_file".to_owned()),
+        }
+    }
+
+    check_creatable_directory(
+        &mut problems,
+        "outbound_queue.path",
+        &config.outbound_queue.path,
+    );
+    check_parent_directory(&mut problems, "home.state_file", &home.state_file);
+    check_parent_directory(
+        &mut problems,
+        "home.run_history_file",
+        &home.run_history_file,
+    );
+    if let Some(log_file) = &config.logging.file {
+        check_creatable_directory(&mut problems, "logging.file.path", &log_file.path);
+    }
+
+    problems
+}
+
+fn check_channel_id(problems: &mut Vec<String>, name: &str, channel_id: u64) {
+    if channel_id == 0 {
+        problems.push(format!("{name} is not set"));
+    }
+}
+
+fn check_channel(
+    problems: &mut Vec<String>,
+    name: &str,
+    channel: &ChannelRef,
+    aliases: &HashMap<String, u64>,
+) {
+    match channel {
+        ChannelRef::Id(0) => problems.push(format!("{name} has channel id 0")),
+        ChannelRef::Id(_) => (),
+        ChannelRef::Alias(alias) if !aliases.contains_key(alias) => {
+            problems.push(format!("{name} uses unknown channel alias {alias:?}"))
+        }
+        ChannelRef::Alias(_) => (),
+    }
+}
+
+/// Topics we publish under can't contain wildcards
+fn check_topic_name(problems: &mut Vec<String>, name: &str, topic: &str) {
+    if topic.is_empty() {
+        problems.push(format!("{name} is empty"));
+    } else if topic.contains(['+', '#']) {
+        problems.push(format!("{name} {topic:?} can't contain wildcards"));
+    }
+}
+
+/// `+` must be a whole level and `#` a whole last level
+fn check_topic_filter(topic: &str) -> Result<(), String> {
+    if topic.is_empty() {
+        return Err("is empty".to_owned());
+    }
+    let levels: Vec<_> = topic.split('/').collect();
+    for (index, level) in levels.iter().enumerate() {
+        let misplaced_hash = level.contains('#') && (*level != "#" || index != levels.len() - 1);
+        let misplaced_plus = level.contains('+') && *level != "+";
+        if misplaced_hash || misplaced_plus {
+            return Err(format!("{topic:?} is not a valid filter"));
+        }
+    }
+    Ok(())
+}
+
+fn check_file(problems: &mut Vec<String>, name: &str, path: &Path) {
+    if !path.is_file() {
+        problems.push(format!("{name} {path:?} does not exist"));
+    }
+}
+
+/// The file is created when needed but its directory has to exist
+/// except for the state directory, which doesn't exist until the service first starts
+fn check_parent_directory(problems: &mut Vec<String>, name: &str, path: &Path) {
+    let directory = parent_directory(path);
+    if !directory.is_dir() && directory != Path::new(STATE_DIRECTORY) {
+        problems.push(format!("{name} directory {directory:?} does not exist"));
+    }
+}
+
+/// Missing queue and log directories are created on startup, only a file in the way is a problem
+fn check_creatable_directory(problems: &mut Vec<String>, name: &str, path: &Path) {
+    let directory = parent_directory(path);
+    if let Some(existing) = directory.ancestors().find(|ancestor| ancestor.exists()) {
+        if !existing.is_dir() {
+            problems.push(format!(
+                "{name} directory {directory:?} can't be created, {existing:?} is not a directory"
+            ));
+        }
+    }
+}
+
+fn parent_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn valid_topic_filters() {
+        for topic in [
+            "zigbee2mqtt/main_door",
+            "zigbee2mqtt/+",
+            "zigbee2mqtt/+/state",
+            "#",
+            "zigbee2mqtt/#",
+            "+/+/#",
+        ] {
+            assert_eq!(check_topic_filter(topic), Ok(()), "{topic}");
+        }
+    }
+
+    #[test]
+    fn invalid_topic_filters() {
+        for topic in [
+            "",
+            "zigbee2mqtt/#/state",
+            "zigbee2mqtt/main#",
+            "zigbee2mqtt/door+",
+            "zigbee2mqtt/++",
+        ] {
+            assert!(check_topic_filter(topic).is_err(), "{topic}");
+        }
+    }
+
+    #[test]
+    fn missing_directory_is_a_problem() {
+        let dir = TempDir::new("config_check").unwrap();
+        let mut problems = vec![];
+        check_parent_directory(&mut problems, "home.state_file", &dir.path().join("mode"));
+        assert!(problems.is_empty());
+        check_parent_directory(
+            &mut problems,
+            "home.state_file",
+            &dir.path().join("missing/mode"),
+        );
+        assert_eq!(problems.len(), 1);
+    }
+
+    #[test]
+    fn missing_queue_or_log_directory_is_created_later() {
+        let dir = TempDir::new("config_check").unwrap();
+        let mut problems = vec![];
+        check_creatable_directory(
+            &mut problems,
+            "logging.file.path",
+            &dir.path().join("a/b.log"),
+        );
+        assert!(problems.is_empty());
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        check_creatable_directory(
+            &mut problems,
+            "logging.file.path",
+            &dir.path().join("file/b.log"),
+        );
+        assert_eq!(problems.len(), 1);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::configuration::{get_configuration, AppConfig};
+use anyhow::Context;
+use log::*;
+use notify::{RecursiveMode, Watcher};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+};
+
+/// Editors tend to write files in several steps
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reload the configuration when its file changes or on SIGHUP
+///
+/// Only device rules, templates, channel aliases, allow-lists and quiet hours
+/// are reapplied, connection settings still need a restart
+pub fn watch_configuration(
+    config_path: Option<PathBuf>,
+    initial: AppConfig,
+) -> anyhow::Result<watch::Receiver<AppConfig>> {
+    let (sender, receiver) = watch::channel(initial);
+    let (file_changed_sender, mut file_changed) = mpsc::channel(1);
+
+    let watched_files = config_files(&config_path);
+    let file_names: Vec<OsString> = watched_files
+        .iter()
+        .filter_map(|file| file.file_name().map(|name| name.to_owned()))
+        .collect();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            Ok(event) => {
+                // config paths usually leave out the extension
+                let relevant = event.paths.iter().any(|path| {
+                    [path.file_name(), path.file_stem()]
+                        .into_iter()
+                        .flatten()
+                        .any(|candidate| file_names.iter().any(|name| name == candidate))
+                });
+                if relevant && !event.kind.is_access() {
+                    // a reload is already pending if the channel is full
+                    let _ = file_changed_sender.try_send(());
+                }
+            }
+            Err(e) => error!("Config file watch error {e}"),
+        }
+    })
+    .context("Failed to create config file watcher")?;
+    // watch directories so we see files replaced by editors
+    for file in &watched_files {
+        let directory = match file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if !directory.is_dir() {
+            // configured from the environment only
+            warn!("Not watching missing config directory {directory:?}");
+            continue;
+        }
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {directory:?}"))?;
+    }
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        // dropping the watcher stops it
+        let _watcher = watcher;
+        loop {
+            tokio::select! {
+                Some(()) = file_changed.recv() => {
+                    tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                    let _ = file_changed.try_recv();
+                    info!("Configuration file changed, reloading");
+                }
+                _ = hangup.recv() => info!("Received SIGHUP, reloading configuration"),
+            }
+            match get_configuration(config_path.clone()) {
+                Ok(mut config) => {
+                    keep_base_route(&mut config, &sender.borrow().mqtt.base_route);
+                    info!("Reloaded configuration, MQTT broker, discord and outbound queue settings apply after a restart");
+                    sender.send_replace(config);
+                }
+                Err(e) => error!("Keeping current configuration, failed reloading {e:?}"),
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Status, error and discovery topics are only set up at startup
+/// so routes keep the running base instead of mixing old and new topics
+fn keep_base_route(config: &mut AppConfig, running: &str) {
+    if config.mqtt.base_route != running {
+        warn!(
+            "Ignoring mqtt.base_route change to {:?}, restart required, keeping {running:?}",
+            config.mqtt.base_route
+        );
+        config.mqtt.base_route = running.to_owned();
+    }
+}
+
+/// Files `get_configuration` reads, usually without extension
+fn config_files(config_path: &Option<PathBuf>) -> Vec<PathBuf> {
+    match config_path {
+        Some(path) => vec![path.clone()],
+        None => vec![
+            PathBuf::from("configuration/settings"),
+            PathBuf::from("configuration/dev_settings"),
+        ],
+    }
+}
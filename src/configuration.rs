@@ -1,9 +1,81 @@
+use anyhow::Context;
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
 use log::*;
-use serde::Deserialize;
-use std::{path::PathBuf, str};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str,
+};
+
+/// Environment variable systemd sets when the unit uses `LoadCredential=`
+const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+const DISCORD_TOKEN_CREDENTIAL: &str = "discord_token";
+const MQTT_PASSWORD_CREDENTIAL: &str = "mqtt_password";
+const ENVIRONMENT_PREFIX: &str = "APP__";
+const ENVIRONMENT_SEPARATOR: &str = "__";
+/// Created by systemd through `StateDirectory=` when the service starts
+pub const STATE_DIRECTORY: &str = "/var/lib/whole_sum_boi";
 
 /// Use default config if no path is provided
 pub fn get_configuration(config: Option<PathBuf>) -> Result<AppConfig, anyhow::Error> {
+    let config = load_sources(config)?;
+    let mut app_config = config.try_deserialize::<AppConfig>()?;
+    if let Some(error) = app_config.resolve_secrets().into_iter().next() {
+        return Err(error);
+    }
+    Ok(app_config)
+}
+
+/// Only the logging section, so logging can be set up before the rest is read
+pub fn get_logging_configuration(config: Option<PathBuf>) -> anyhow::Result<LoggingConfig> {
+    let config = load_sources(config)?;
+    let mut problems = vec![];
+    optional_section(&config, "logging", &mut problems)
+        .ok_or_else(|| anyhow::anyhow!(problems.join(", ")))
+}
+
+/// Like `get_configuration` but reports every broken section and secret
+/// instead of stopping at the first, the config is None if a section is broken
+pub fn check_configuration_sources(config: Option<PathBuf>) -> (Option<AppConfig>, Vec<String>) {
+    let config = match load_sources(config) {
+        Ok(config) => config,
+        Err(e) => return (None, vec![format!("{e:#}")]),
+    };
+    let mut problems = vec![];
+    // sections and defaults match AppConfig
+    let mqtt = section(&config, "mqtt", &mut problems);
+    let discord = section(&config, "discord", &mut problems);
+    let home = section(&config, "home", &mut problems);
+    let outbound_queue = optional_section(&config, "outbound_queue", &mut problems);
+    let logging = optional_section(&config, "logging", &mut problems);
+    let metrics = optional_section(&config, "metrics", &mut problems);
+    let (Some(mqtt), Some(discord), Some(home), Some(outbound_queue), Some(logging), Some(metrics)) =
+        (mqtt, discord, home, outbound_queue, logging, metrics)
+    else {
+        return (None, problems);
+    };
+    let mut app_config = AppConfig {
+        mqtt,
+        discord,
+        home,
+        outbound_queue,
+        logging,
+        metrics,
+    };
+    problems.extend(
+        app_config
+            .resolve_secrets()
+            .into_iter()
+            .map(|e| format!("{e:#}")),
+    );
+    (Some(app_config), problems)
+}
+
+fn load_sources(config: Option<PathBuf>) -> anyhow::Result<config::Config> {
     let mut config_builder = config::Config::builder();
 
     if let Some(config) = config {
@@ -16,15 +88,135 @@ pub fn get_configuration(config: Option<PathBuf>) -> Result<AppConfig, anyhow::E
         ));
     } else {
         info!("Using default configuration");
+        // optional so everything can come from the environment
         config_builder = config_builder
-            .add_source(config::File::with_name("configuration/settings"))
-            .add_source(config::File::with_name("configuration/dev_settings"));
+            .add_source(config::File::with_name("configuration/settings").required(false))
+            .add_source(config::File::with_name("configuration/dev_settings").required(false));
     }
 
-    config_builder = config_builder.add_source(config::Environment::with_prefix("APP"));
-    let config = config_builder.build()?;
+    // nested fields are separated by double underscores, e.g. APP__DISCORD__TOKEN
+    config_builder = config_builder.add_source(
+        config::Environment::with_prefix("APP")
+            .prefix_separator(ENVIRONMENT_SEPARATOR)
+            .separator(ENVIRONMENT_SEPARATOR),
+    );
+    for (key, value) in json_environment_overrides(std::env::vars()) {
+        config_builder = config_builder.set_override(key, value)?;
+    }
+    Ok(config_builder.build()?)
+}
+
+/// `config::Config::get` replaces the key of the field that failed with the section
+/// so the section is read as a value first to keep it in the error
+fn section<T: DeserializeOwned>(
+    config: &config::Config,
+    key: &str,
+    problems: &mut Vec<String>,
+) -> Option<T> {
+    config
+        .get::<config::Value>(key)
+        .and_then(|value| value.try_deserialize())
+        .map_err(|e| problems.push(format!("{key}: {e}")))
+        .ok()
+}
+
+fn optional_section<T: DeserializeOwned + Default>(
+    config: &config::Config,
+    key: &str,
+    problems: &mut Vec<String>,
+) -> Option<T> {
+    match config.get::<config::Value>(key) {
+        Ok(value) => value
+            .try_deserialize()
+            .map_err(|e| problems.push(format!("{key}: {e}")))
+            .ok(),
+        Err(config::ConfigError::NotFound(_)) => Some(T::default()),
+        Err(e) => {
+            problems.push(format!("{key}: {e}"));
+            None
+        }
+    }
+}
 
-    Ok(config.try_deserialize::<AppConfig>()?)
+/// Environment variables can't spell out lists so they can also be given as JSON
+/// e.g. APP__HOME__DEVICES='[{"name": "main_door", "critical": true}]'
+///
+/// Only values that parse as a JSON array or object are returned,
+/// anything else is left to the environment source as a string
+fn json_environment_overrides(
+    variables: impl Iterator<Item = (String, String)>,
+) -> Vec<(String, config::Value)> {
+    variables
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENVIRONMENT_PREFIX)?;
+            if !value.trim_start().starts_with(['[', '{']) {
+                return None;
+            }
+            let json = serde_json::from_str(&value).ok()?;
+            let key = key
+                .split(ENVIRONMENT_SEPARATOR)
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>()
+                .join(".");
+            Some((key, config_value(json)))
+        })
+        .collect()
+}
+
+fn config_value(json: serde_json::Value) -> config::Value {
+    let kind = match json {
+        serde_json::Value::Null => config::ValueKind::Nil,
+        serde_json::Value::Bool(value) => config::ValueKind::Boolean(value),
+        serde_json::Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(value), _) => config::ValueKind::I64(value),
+            (None, Some(value)) => config::ValueKind::U64(value),
+            (None, None) => config::ValueKind::Float(number.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(value) => config::ValueKind::String(value),
+        serde_json::Value::Array(values) => {
+            config::ValueKind::Array(values.into_iter().map(config_value).collect())
+        }
+        serde_json::Value::Object(values) => config::ValueKind::Table(
+            values
+                .into_iter()
+                .map(|(key, value)| (key, config_value(value)))
+                .collect(),
+        ),
+    };
+    config::Value::new(None, kind)
+}
+
+/// String that is kept out of logs
+#[derive(Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.trim().is_empty()
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed reading secret from {path:?}"))?;
+        Ok(Self(secret.trim_end().to_owned()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"[redacted]\"")
+    }
+}
+
+/// Path of a credential passed in by systemd, if it exists
+fn systemd_credential(name: &str) -> Option<PathBuf> {
+    let path = Path::new(&std::env::var_os(CREDENTIALS_DIRECTORY)?).join(name);
+    path.is_file().then_some(path)
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -32,6 +224,243 @@ pub struct AppConfig {
     pub mqtt: MqttConfig,
     pub discord: DiscordConfig,
     pub home: HomeSettings,
+    #[serde(default)]
+    pub outbound_queue: OutboundQueueConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+}
+
+impl AppConfig {
+    /// Fill in secrets from their files or systemd credentials when they aren't set inline
+    /// returns the secrets that couldn't be read
+    fn resolve_secrets(&mut self) -> Vec<anyhow::Error> {
+        let mut errors = vec![];
+        let discord = &mut self.discord;
+        if discord.token.is_empty() {
+            if let Some(token_file) = discord
+                .token_file
+                .clone()
+                .or_else(|| systemd_credential(DISCORD_TOKEN_CREDENTIAL))
+            {
+                match Secret::read(&token_file) {
+                    Ok(token) => discord.token = token,
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+        let mqtt = &mut self.mqtt;
+        if mqtt.password.is_none() {
+            if let Some(password_file) = mqtt
+                .password_file
+                .clone()
+                .or_else(|| systemd_credential(MQTT_PASSWORD_CREDENTIAL))
+            {
+                match Secret::read(&password_file) {
+                    Ok(password) => mqtt.password = Some(password),
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// Persistent queue for messages sent to discord
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutboundQueueConfig {
+    #[serde(default = "default_outbound_queue_path")]
+    pub path: PathBuf,
+    /// Messages older than this are moved to the dead letter table
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+    #[serde(default = "default_initial_backoff_secs")]
+    pub initial_backoff_secs: u64,
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// How long shutdown waits for pending messages to be sent
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for OutboundQueueConfig {
+    fn default() -> Self {
+        Self {
+            path: default_outbound_queue_path(),
+            max_age_secs: default_max_age_secs(),
+            initial_backoff_secs: default_initial_backoff_secs(),
+            max_backoff_secs: default_max_backoff_secs(),
+            rate_limit: RateLimitConfig::default(),
+            drain_timeout_secs: default_drain_timeout_secs(),
+        }
+    }
+}
+
+/// HTTP listener serving Prometheus metrics
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_metrics_listen_address")]
+    pub listen_address: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_address: default_metrics_listen_address(),
+        }
+    }
+}
+
+fn default_metrics_listen_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9898))
+}
+
+/// Log levels and outputs, only read on startup
+#[derive(Deserialize, Debug, Clone)]
+pub struct LoggingConfig {
+    /// Level for our own crate
+    #[serde(default = "default_log_level", deserialize_with = "log_level")]
+    pub level: LevelFilter,
+    /// Levels for other modules such as serenity or rumqttc, these are off unless listed
+    #[serde(default, deserialize_with = "module_log_levels")]
+    pub modules: HashMap<String, LevelFilter>,
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+    #[serde(default)]
+    pub journald: JournaldMode,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            modules: HashMap::new(),
+            file: None,
+            journald: JournaldMode::default(),
+        }
+    }
+}
+
+const fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+fn log_level<'de, D>(deserializer: D) -> Result<LevelFilter, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn module_log_levels<'de, D>(deserializer: D) -> Result<HashMap<String, LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(module, level)| Ok((module, level.parse().map_err(serde::de::Error::custom)?)))
+        .collect()
+}
+
+/// Log file rotated once it grows over max_size_bytes
+#[derive(Deserialize, Debug, Clone)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_log_max_size_bytes")]
+    pub max_size_bytes: u64,
+    /// Rotated files kept next to the current one
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+}
+
+const fn default_log_max_size_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+const fn default_log_max_files() -> usize {
+    5
+}
+
+/// When to log to the systemd journal instead of the terminal
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JournaldMode {
+    /// Use the journal when systemd connected our output to it
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Limits on how many messages we send per minute, 0 means unlimited
+#[derive(Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_per_channel_per_minute")]
+    pub per_channel_per_minute: usize,
+    #[serde(default = "default_global_per_minute")]
+    pub global_per_minute: usize,
+    #[serde(default)]
+    pub policy: RateLimitPolicy,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_channel_per_minute: default_per_channel_per_minute(),
+            global_per_minute: default_global_per_minute(),
+            policy: RateLimitPolicy::default(),
+        }
+    }
+}
+
+const fn default_per_channel_per_minute() -> usize {
+    20
+}
+
+const fn default_global_per_minute() -> usize {
+    60
+}
+
+/// What happens to messages over the rate limit
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitPolicy {
+    /// Drop the message and report it on the errors topic
+    Drop,
+    /// Keep the message until the limit allows sending it
+    Delay,
+    /// Merge waiting messages for the same channel into one
+    /// when they have the same mentions and silent setting
+    #[default]
+    Coalesce,
+}
+
+fn default_outbound_queue_path() -> PathBuf {
+    Path::new(STATE_DIRECTORY).join("outbound_queue.sqlite")
+}
+
+const fn default_max_age_secs() -> u64 {
+    24 * 60 * 60
+}
+
+const fn default_initial_backoff_secs() -> u64 {
+    2
+}
+
+const fn default_max_backoff_secs() -> u64 {
+    5 * 60
+}
+
+const fn default_drain_timeout_secs() -> u64 {
+    10
 }
 
 // weird serde default thing
@@ -48,15 +477,637 @@ pub struct MqttConfig {
     #[serde(default = "default_mqtt_port")]
     pub broker_port: u16,
     pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    /// Read the password from this file if `password` isn't set,
+    /// falls back to the systemd credential `mqtt_password`
+    pub password_file: Option<PathBuf>,
+    #[serde(default)]
+    pub tls: MqttTlsConfig,
+    #[serde(default)]
+    pub outbound_routes: OutboundRoutes,
+    /// QoS for subscriptions without their own entry in `subscriptions`
+    #[serde(default)]
+    pub subscription_qos: Qos,
+    #[serde(default)]
+    pub subscriptions: Vec<SubscriptionConfig>,
+    #[serde(default)]
+    pub publish: PublishStreams,
+    #[serde(default)]
+    pub home_assistant: HomeAssistantConfig,
+}
+
+impl MqttConfig {
+    pub fn subscription_qos(&self, topic: &str) -> Qos {
+        self.subscriptions
+            .iter()
+            .find(|subscription| subscription.topic == topic)
+            .map(|subscription| subscription.qos)
+            .unwrap_or(self.subscription_qos)
+    }
+}
+
+/// MQTT quality of service level, 0, 1 or 2
+// variant names mirror rumqttc::QoS
+#[allow(clippy::enum_variant_names)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "u8")]
+pub enum Qos {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Qos::AtMostOnce),
+            1 => Ok(Qos::AtLeastOnce),
+            2 => Ok(Qos::ExactlyOnce),
+            other => Err(format!("Invalid QoS {other}, expected 0, 1 or 2")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SubscriptionConfig {
+    /// Topic filter exactly as the route is registered, e.g. "zigbee2mqtt/main_door"
+    pub topic: String,
+    pub qos: Qos,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct PublishConfig {
+    #[serde(default)]
+    pub qos: Qos,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl PublishConfig {
+    const fn retained() -> Self {
+        Self {
+            qos: Qos::AtMostOnce,
+            retain: true,
+        }
+    }
+
+    const fn not_retained() -> Self {
+        Self {
+            qos: Qos::AtMostOnce,
+            retain: false,
+        }
+    }
+}
+
+/// Settings for each stream of messages we publish
+#[derive(Deserialize, Debug, Clone)]
+pub struct PublishStreams {
+    #[serde(default = "PublishConfig::not_retained")]
+    pub new_message: PublishConfig,
+    #[serde(default = "PublishConfig::retained")]
+    pub house_mode: PublishConfig,
+    #[serde(default = "PublishConfig::not_retained")]
+    pub errors: PublishConfig,
+    /// Also used for the offline last will
+    #[serde(default = "PublishConfig::retained")]
+    pub status: PublishConfig,
+    #[serde(default = "PublishConfig::retained")]
+    pub discord_status: PublishConfig,
+}
+
+impl Default for PublishStreams {
+    fn default() -> Self {
+        Self {
+            new_message: PublishConfig::not_retained(),
+            house_mode: PublishConfig::retained(),
+            errors: PublishConfig::not_retained(),
+            status: PublishConfig::retained(),
+            discord_status: PublishConfig::retained(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MqttTlsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// PEM CA certificate used to verify the broker
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate for client authentication
+    pub client_certificate_file: Option<PathBuf>,
+    /// PEM client key for client authentication
+    pub client_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key_type: ClientKeyType,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientKeyType {
+    #[default]
+    Rsa,
+    Ecc,
+}
+
+/// Home Assistant MQTT discovery of the bot's entities
+#[derive(Deserialize, Debug, Clone)]
+pub struct HomeAssistantConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Channel for the notify entity, the notification channel if not set
+    pub notify_channel: Option<ChannelRef>,
+    /// Each one becomes a button that posts its message
+    #[serde(default)]
+    pub quick_messages: Vec<QuickMessage>,
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            discovery_prefix: default_discovery_prefix(),
+            notify_channel: None,
+            quick_messages: Vec::new(),
+        }
+    }
+}
+
+fn default_discovery_prefix() -> String {
+    String::from("homeassistant")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuickMessage {
+    pub name: String,
+    pub channel: ChannelRef,
+    pub content: String,
+}
+
+/// Settings for the routes that let MQTT clients post to discord
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OutboundRoutes {
+    #[serde(default)]
+    pub say_channel: OutboundRouteConfig,
+    #[serde(default)]
+    pub send_file_channel: OutboundRouteConfig,
+    #[serde(default)]
+    pub show_typing_channel: OutboundRouteConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OutboundRouteConfig {
+    #[serde(default)]
+    pub mentions: MentionPolicy,
+    /// Channels this route may post to, any channel if not set
+    #[serde(default)]
+    pub allowed_channels: Option<Vec<ChannelRef>>,
+}
+
+impl OutboundRouteConfig {
+    pub fn is_channel_allowed(&self, channel_id: u64, aliases: &HashMap<String, u64>) -> bool {
+        self.allowed_channels
+            .as_ref()
+            .map(|allowed| {
+                allowed
+                    .iter()
+                    .any(|channel| channel.resolve(aliases) == Some(channel_id))
+            })
+            .unwrap_or(true)
+    }
+}
+
+/// Channel given either by id or by a name from `channel_aliases`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelRef {
+    Id(u64),
+    Alias(String),
+}
+
+impl<'de> Deserialize<'de> for ChannelRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum IdOrName {
+            Id(u64),
+            Name(String),
+        }
+
+        // environment variables are always strings so ids come in as digits
+        Ok(match IdOrName::deserialize(deserializer)? {
+            IdOrName::Id(id) => ChannelRef::Id(id),
+            IdOrName::Name(name)
+                if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                name.parse()
+                    .map(ChannelRef::Id)
+                    .map_err(serde::de::Error::custom)?
+            }
+            IdOrName::Name(name) => ChannelRef::Alias(name),
+        })
+    }
+}
+
+impl ChannelRef {
+    pub fn resolve(&self, aliases: &HashMap<String, u64>) -> Option<u64> {
+        match self {
+            ChannelRef::Id(id) => Some(*id),
+            ChannelRef::Alias(alias) => aliases.get(alias).copied(),
+        }
+    }
+}
+
+/// Who messages from MQTT are allowed to ping
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MentionPolicy {
+    /// Mentions in the content never ping anyone
+    #[default]
+    None,
+    /// Users and roles listed in the payload can be pinged, never @everyone
+    Payload,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct DiscordConfig {
-    pub token: String,
+    #[serde(default)]
+    pub token: Secret,
+    /// Read the token from this file if `token` isn't set,
+    /// falls back to the systemd credential `discord_token`
+    pub token_file: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct HomeSettings {
     pub notification_discord_channel: u64,
     pub spam_channel_id: u64,
+    /// Names MQTT payloads can use instead of channel ids
+    #[serde(default)]
+    pub channel_aliases: HashMap<String, u64>,
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHours>,
+    /// Where the house mode is persisted
+    #[serde(default = "default_state_file")]
+    pub state_file: PathBuf,
+    #[serde(default)]
+    pub devices: Vec<DeviceRule>,
+    /// Who gets pinged for devices without their own mentions
+    #[serde(default = "default_mentions")]
+    pub default_mentions: MentionTargets,
+    /// Records when we started and were last running to report the previous run's uptime
+    #[serde(default = "default_run_history_file")]
+    pub run_history_file: PathBuf,
+    #[serde(default)]
+    pub startup_announcement: StartupAnnouncement,
+    #[serde(default)]
+    pub shutdown_announcement: ShutdownAnnouncement,
+    #[serde(default)]
+    pub mode_command: ModeCommandConfig,
+}
+
+impl HomeSettings {
+    pub fn device_rule(&self, device: &str) -> Option<&DeviceRule> {
+        self.devices.iter().find(|rule| rule.name == device)
+    }
+
+    /// Listed users and roles can change the house mode from any channel
+    /// with nobody listed changes are only accepted in the notification channel
+    pub fn can_change_mode(&self, user: u64, roles: &[u64], channel: u64) -> bool {
+        let allowed = &self.mode_command;
+        if allowed.users.is_empty() && allowed.roles.is_empty() {
+            return channel == self.notification_discord_channel;
+        }
+        allowed.users.contains(&user) || roles.iter().any(|role| allowed.roles.contains(role))
+    }
+}
+
+fn default_mentions() -> MentionTargets {
+    MentionTargets {
+        everyone: true,
+        ..Default::default()
+    }
+}
+
+/// Users and roles pinged by a notification
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MentionTargets {
+    #[serde(default)]
+    pub users: Vec<u64>,
+    #[serde(default)]
+    pub roles: Vec<u64>,
+    #[serde(default)]
+    pub everyone: bool,
+}
+
+/// Notification settings for a single device
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceRule {
+    /// Device name such as "main_door" or "switch/desk"
+    pub name: String,
+    pub mentions: Option<MentionTargets>,
+    /// Always send to the notification channel with mentions
+    #[serde(default)]
+    pub critical: bool,
+}
+
+fn default_state_file() -> PathBuf {
+    Path::new(STATE_DIRECTORY).join("state.json")
+}
+
+fn default_run_history_file() -> PathBuf {
+    Path::new(STATE_DIRECTORY).join("run_history.json")
+}
+
+/// Who can change the house mode with the `!mode` command, anyone can view it
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ModeCommandConfig {
+    #[serde(default)]
+    pub users: Vec<u64>,
+    #[serde(default)]
+    pub roles: Vec<u64>,
+}
+
+/// Message posted once the discord gateway is ready
+#[derive(Deserialize, Debug, Clone)]
+pub struct StartupAnnouncement {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// The notification channel if not set
+    pub channel: Option<ChannelRef>,
+    /// Placeholders: {version}, {host}, {previous_uptime} and {config_summary}
+    #[serde(default = "default_announcement_template")]
+    pub template: String,
+}
+
+impl Default for StartupAnnouncement {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            channel: None,
+            template: default_announcement_template(),
+        }
+    }
+}
+
+fn default_announcement_template() -> String {
+    String::from("WholeSumBoi is online")
+}
+
+/// Message posted when we are asked to stop
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShutdownAnnouncement {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The notification channel if not set
+    pub channel: Option<ChannelRef>,
+    /// Placeholders: {version}, {host}, {uptime} and {config_summary}
+    #[serde(default = "default_shutdown_template")]
+    pub template: String,
+}
+
+impl Default for ShutdownAnnouncement {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            channel: None,
+            template: default_shutdown_template(),
+        }
+    }
+}
+
+fn default_shutdown_template() -> String {
+    String::from("WholeSumBoi is shutting down")
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+const fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+/// What happens to a notification that arrives during quiet hours
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QuietHoursAction {
+    /// Drop the notification
+    Suppress,
+    /// Collect notifications and post them together once quiet hours end
+    #[default]
+    Digest,
+    /// Send the notification without a push notification
+    Silent,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    #[serde(default)]
+    pub action: QuietHoursAction,
+    /// Devices this applies to, empty means all devices
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Channels this applies to, empty means all channels
+    #[serde(default)]
+    pub channels: Vec<u64>,
+    /// Let critical events such as the door opening through as normal
+    #[serde(default = "default_true")]
+    pub allow_critical: bool,
+}
+
+impl QuietHours {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let local_time = now.with_timezone(&self.timezone).time();
+        if self.start <= self.end {
+            self.start <= local_time && local_time < self.end
+        } else {
+            // window wraps around midnight
+            local_time >= self.start || local_time < self.end
+        }
+    }
+
+    pub fn applies_to(&self, device: &str, channel_id: u64) -> bool {
+        (self.devices.is_empty() || self.devices.iter().any(|name| name == device))
+            && (self.channels.is_empty() || self.channels.contains(&channel_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn quiet_hours(start: &str, end: &str, timezone: Tz) -> QuietHours {
+        QuietHours {
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            timezone,
+            action: QuietHoursAction::Digest,
+            devices: vec![],
+            channels: vec![],
+            allow_critical: true,
+        }
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let quiet_hours = quiet_hours("13:00", "15:00", Tz::UTC);
+        assert!(!quiet_hours.is_active(utc(2023, 6, 1, 12, 59)));
+        assert!(quiet_hours.is_active(utc(2023, 6, 1, 13, 0)));
+        assert!(quiet_hours.is_active(utc(2023, 6, 1, 14, 59)));
+        assert!(!quiet_hours.is_active(utc(2023, 6, 1, 15, 0)));
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        let quiet_hours = quiet_hours("23:00", "07:00", Tz::UTC);
+        assert!(!quiet_hours.is_active(utc(2023, 6, 1, 22, 59)));
+        assert!(quiet_hours.is_active(utc(2023, 6, 1, 23, 0)));
+        assert!(quiet_hours.is_active(utc(2023, 6, 2, 0, 30)));
+        assert!(quiet_hours.is_active(utc(2023, 6, 2, 6, 59)));
+        assert!(!quiet_hours.is_active(utc(2023, 6, 2, 7, 0)));
+    }
+
+    #[test]
+    fn window_follows_local_time_across_dst() {
+        let quiet_hours = quiet_hours("23:00", "07:00", chrono_tz::Europe::Dublin);
+        // 22:30 UTC is 22:30 in winter but 23:30 in summer
+        assert!(!quiet_hours.is_active(utc(2023, 1, 15, 22, 30)));
+        assert!(quiet_hours.is_active(utc(2023, 7, 15, 22, 30)));
+        // 06:30 UTC is 06:30 in winter but 07:30 in summer
+        assert!(quiet_hours.is_active(utc(2023, 1, 15, 6, 30)));
+        assert!(!quiet_hours.is_active(utc(2023, 7, 15, 6, 30)));
+    }
+
+    #[test]
+    fn window_on_the_night_clocks_change() {
+        // clocks in Dublin jump from 01:00 to 02:00 on 2023-03-26
+        let quiet_hours = quiet_hours("02:00", "03:00", chrono_tz::Europe::Dublin);
+        assert!(!quiet_hours.is_active(utc(2023, 3, 26, 0, 30)));
+        assert!(quiet_hours.is_active(utc(2023, 3, 26, 1, 0)));
+        assert!(!quiet_hours.is_active(utc(2023, 3, 26, 2, 0)));
+    }
+
+    fn home_settings(mode_command: ModeCommandConfig) -> HomeSettings {
+        serde_json::from_value::<HomeSettings>(serde_json::json!({
+            "notification_discord_channel": 10,
+            "spam_channel_id": 20,
+        }))
+        .map(|home| HomeSettings {
+            mode_command,
+            ..home
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn mode_changes_only_in_notification_channel_without_allow_list() {
+        let home = home_settings(ModeCommandConfig::default());
+        assert!(home.can_change_mode(1, &[], 10));
+        assert!(!home.can_change_mode(1, &[], 20));
+    }
+
+    #[test]
+    fn mode_changes_only_by_listed_users_and_roles() {
+        let home = home_settings(ModeCommandConfig {
+            users: vec![1],
+            roles: vec![100],
+        });
+        assert!(home.can_change_mode(1, &[], 20));
+        assert!(home.can_change_mode(2, &[5, 100], 20));
+        assert!(!home.can_change_mode(2, &[5], 20));
+        assert!(!home.can_change_mode(2, &[], 10));
+    }
+
+    #[test]
+    fn applies_to_all_devices_and_channels_when_empty() {
+        let quiet_hours = quiet_hours("23:00", "07:00", Tz::UTC);
+        assert!(quiet_hours.applies_to("main_door", 1));
+        assert!(quiet_hours.applies_to("motion/hall", 2));
+    }
+
+    #[test]
+    fn applies_to_listed_devices_and_channels() {
+        let mut quiet_hours = quiet_hours("23:00", "07:00", Tz::UTC);
+        quiet_hours.devices = vec!["motion/hall".to_owned()];
+        quiet_hours.channels = vec![2];
+        assert!(quiet_hours.applies_to("motion/hall", 2));
+        assert!(!quiet_hours.applies_to("main_door", 2));
+        assert!(!quiet_hours.applies_to("motion/hall", 1));
+    }
+
+    #[test]
+    fn channel_ref_from_digits_is_an_id() {
+        let channel = |value| serde_json::from_value::<ChannelRef>(value).unwrap();
+        assert_eq!(channel(serde_json::json!(123)), ChannelRef::Id(123));
+        assert_eq!(channel(serde_json::json!("123")), ChannelRef::Id(123));
+        assert_eq!(
+            channel(serde_json::json!("alerts")),
+            ChannelRef::Alias("alerts".to_owned())
+        );
+        assert_eq!(
+            channel(serde_json::json!("+123")),
+            ChannelRef::Alias("+123".to_owned())
+        );
+    }
+
+    #[test]
+    fn json_lists_from_the_environment_become_overrides() {
+        let overrides = json_environment_overrides(
+            [
+                ("APP__HOME__MODE_COMMAND__USERS", "[1, 2]"),
+                ("APP__DISCORD__TOKEN", "secret"),
+                ("APP__HOME__STARTUP_ANNOUNCEMENT__TEMPLATE", "[bot] {host}"),
+                ("OTHER__LIST", "[1]"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.to_owned())),
+        );
+        assert_eq!(overrides.len(), 1);
+        let (key, value) = overrides.into_iter().next().unwrap();
+        assert_eq!(key, "home.mode_command.users");
+        assert_eq!(value.try_deserialize::<Vec<u64>>().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn non_json_environment_values_with_brackets_stay_strings() {
+        let variables: std::collections::HashMap<_, _> = [
+            ("APP__HOME__STARTUP_ANNOUNCEMENT__TEMPLATE", "[bot] {host}"),
+            ("APP__DISCORD__TOKEN", "[not json"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+        let mut builder = config::Config::builder().add_source(
+            config::Environment::with_prefix("APP")
+                .prefix_separator(ENVIRONMENT_SEPARATOR)
+                .separator(ENVIRONMENT_SEPARATOR)
+                .source(Some(variables.clone())),
+        );
+        for (key, value) in json_environment_overrides(variables.into_iter()) {
+            builder = builder.set_override(key, value).unwrap();
+        }
+        let config = builder.build().unwrap();
+        assert_eq!(
+            config
+                .get_string("home.startup_announcement.template")
+                .unwrap(),
+            "[bot] {host}"
+        );
+        assert_eq!(config.get_string("discord.token").unwrap(), "[not json");
+    }
 }
@@ -0,0 +1,112 @@
+use crate::metrics::metrics;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serenity::{client::bridge::gateway::ShardManager, gateway::ConnectionStage};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{watch, Mutex};
+
+/// Shard latency is only measured on heartbeats so there's no point polling faster
+const LATENCY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiscordConnectionState {
+    pub connected: bool,
+    pub stage: Option<String>,
+    /// Shard id and shard count
+    pub shard: Option<[u64; 2]>,
+    pub latency_ms: Option<u64>,
+    pub guild_count: usize,
+    pub user: Option<String>,
+    pub last_change: Option<DateTime<Utc>>,
+}
+
+/// Tracks the state of the discord gateway connection
+pub struct DiscordConnectionTracker {
+    sender: watch::Sender<DiscordConnectionState>,
+}
+
+impl DiscordConnectionTracker {
+    pub fn new() -> Arc<Self> {
+        let (sender, _) = watch::channel(DiscordConnectionState::default());
+        Arc::new(Self { sender })
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<DiscordConnectionState> {
+        self.sender.subscribe()
+    }
+
+    pub fn get(&self) -> DiscordConnectionState {
+        self.sender.borrow().clone()
+    }
+
+    pub fn ready(&self, user: String, shard: Option<[u64; 2]>) {
+        self.sender.send_modify(|state| {
+            if state.user.is_some() {
+                // a new session after the first one
+                metrics().reconnects.with_label_values(&["discord"]).inc();
+            }
+            state.connected = true;
+            state.stage = Some(ConnectionStage::Connected.to_string());
+            state.shard = shard;
+            state.user = Some(user);
+            state.last_change = Some(Utc::now());
+        });
+    }
+
+    pub fn resumed(&self) {
+        metrics().reconnects.with_label_values(&["discord"]).inc();
+        self.sender.send_modify(|state| {
+            state.connected = true;
+            state.stage = Some(ConnectionStage::Connected.to_string());
+            state.last_change = Some(Utc::now());
+        });
+    }
+
+    pub fn stage_changed(&self, stage: ConnectionStage) {
+        self.sender.send_modify(|state| {
+            let connected = stage == ConnectionStage::Connected;
+            if state.connected != connected {
+                state.last_change = Some(Utc::now());
+            }
+            state.connected = connected;
+            state.stage = Some(stage.to_string());
+        });
+    }
+
+    pub fn guilds_available(&self, guild_count: usize) {
+        self.sender.send_if_modified(|state| {
+            let modified = state.guild_count != guild_count;
+            state.guild_count = guild_count;
+            modified
+        });
+    }
+
+    fn latency(&self, latency: Option<Duration>) {
+        let latency_ms = latency.map(|latency| latency.as_millis() as u64);
+        self.sender.send_if_modified(|state| {
+            let modified = state.latency_ms != latency_ms;
+            state.latency_ms = latency_ms;
+            modified
+        });
+    }
+
+    /// Periodically record the heartbeat latency of our shard
+    pub fn start_latency_task(self: &Arc<Self>, shard_manager: Arc<Mutex<ShardManager>>) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(LATENCY_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let runners = shard_manager.lock().await.runners.clone();
+                // we only ever run a single shard
+                let latency = runners
+                    .lock()
+                    .await
+                    .values()
+                    .next()
+                    .and_then(|runner| runner.latency);
+                tracker.latency(latency);
+            }
+        });
+    }
+}
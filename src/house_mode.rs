@@ -0,0 +1,131 @@
+use anyhow::Context;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::watch;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HouseMode {
+    #[default]
+    Home,
+    Away,
+    Night,
+    Vacation,
+}
+
+impl HouseMode {
+    /// Nobody is home so sensor events should reach people's phones
+    pub fn is_away(&self) -> bool {
+        matches!(self, HouseMode::Away | HouseMode::Vacation)
+    }
+}
+
+impl fmt::Display for HouseMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HouseMode::Home => "home",
+            HouseMode::Away => "away",
+            HouseMode::Night => "night",
+            HouseMode::Vacation => "vacation",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for HouseMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "home" => Ok(HouseMode::Home),
+            "away" => Ok(HouseMode::Away),
+            "night" => Ok(HouseMode::Night),
+            "vacation" => Ok(HouseMode::Vacation),
+            other => anyhow::bail!("Unknown house mode {other:?}"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PersistedState {
+    mode: HouseMode,
+}
+
+/// Current house mode persisted to disk across restarts
+pub struct HouseModeStore {
+    state_file: PathBuf,
+    sender: watch::Sender<HouseMode>,
+}
+
+impl HouseModeStore {
+    pub fn load(state_file: PathBuf) -> Arc<Self> {
+        let state = match std::fs::read_to_string(&state_file) {
+            Ok(data) => serde_json::from_str::<PersistedState>(&data).unwrap_or_else(|e| {
+                error!("Failed parsing state file {:?} {e}", state_file);
+                PersistedState::default()
+            }),
+            Err(e) => {
+                warn!("Failed reading state file {:?} {e}", state_file);
+                PersistedState::default()
+            }
+        };
+        info!("House mode is {}", state.mode);
+        let (sender, _) = watch::channel(state.mode);
+        Arc::new(Self { state_file, sender })
+    }
+
+    pub fn get(&self) -> HouseMode {
+        *self.sender.borrow()
+    }
+
+    pub fn set(&self, mode: HouseMode) -> anyhow::Result<()> {
+        let json = serde_json::to_string(&PersistedState { mode })?;
+        write_atomically(&self.state_file, json.as_bytes())
+            .with_context(|| format!("Failed writing state file {:?}", self.state_file))?;
+        info!("House mode set to {mode}");
+        self.sender.send_replace(mode);
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<HouseMode> {
+        self.sender.subscribe()
+    }
+}
+
+/// Write to a temporary file and rename it over the target
+/// so a crash mid-write leaves either the old or the new contents
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn mode_is_persisted_across_loads() {
+        let dir = TempDir::new("house_mode").unwrap();
+        let state_file = dir.path().join("state.json");
+        HouseModeStore::load(state_file.clone())
+            .set(HouseMode::Away)
+            .unwrap();
+        assert_eq!(HouseModeStore::load(state_file).get(), HouseMode::Away);
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1, "temporary file was left behind");
+    }
+}
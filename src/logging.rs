@@ -0,0 +1,286 @@
+use crate::configuration::{JournaldMode, LogFileConfig, LoggingConfig};
+use anyhow::Context;
+use log::*;
+use simplelog::*;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// Set by systemd when our stdout or stderr is connected to the journal
+const JOURNAL_STREAM: &str = "JOURNAL_STREAM";
+
+pub fn setup_logging(config: &LoggingConfig) -> anyhow::Result<()> {
+    let rules = log_rules(config);
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
+
+    if use_journald(config.journald)? {
+        let socket = Arc::new(UnixDatagram::unbound().context("Failed to open journald socket")?);
+        for rule in &rules {
+            loggers.push(Box::new(JournaldLogger {
+                rule: rule.clone(),
+                socket: socket.clone(),
+            }));
+        }
+    } else {
+        for rule in &rules {
+            loggers.push(TermLogger::new(
+                rule.level,
+                rule.config(),
+                TerminalMode::Mixed,
+                ColorChoice::Auto,
+            ));
+        }
+    }
+
+    if let Some(file_config) = &config.file {
+        let file = RotatingFile::open(file_config)?;
+        for rule in &rules {
+            loggers.push(WriteLogger::new(rule.level, rule.config(), file.clone()));
+        }
+    }
+
+    CombinedLogger::init(loggers).context("Failed to set up logging")?;
+    Ok(())
+}
+
+fn use_journald(mode: JournaldMode) -> anyhow::Result<bool> {
+    let available = Path::new(JOURNALD_SOCKET).exists();
+    match mode {
+        JournaldMode::Auto => Ok(available && std::env::var_os(JOURNAL_STREAM).is_some()),
+        JournaldMode::Always if available => Ok(true),
+        JournaldMode::Always => anyhow::bail!("journald socket {} not found", JOURNALD_SOCKET),
+        JournaldMode::Never => Ok(false),
+    }
+}
+
+/// Level for one module
+/// more specific modules with their own level are ignored so they aren't logged twice
+#[derive(Debug, Clone)]
+struct LogRule {
+    module: String,
+    level: LevelFilter,
+    ignore: Vec<String>,
+}
+
+impl LogRule {
+    fn matches(&self, target: &str) -> bool {
+        target.starts_with(&self.module) && !self.ignore.iter().any(|m| target.starts_with(m))
+    }
+
+    fn config(&self) -> simplelog::Config {
+        let mut builder = ConfigBuilder::new();
+        builder.add_filter_allow(self.module.clone());
+        for module in &self.ignore {
+            builder.add_filter_ignore(module.clone());
+        }
+        builder.build()
+    }
+}
+
+/// Our crate logs at the configured level, other modules only when listed
+fn log_rules(config: &LoggingConfig) -> Vec<LogRule> {
+    let mut levels = config.modules.clone();
+    levels.insert(CRATE_NAME.to_owned(), config.level);
+
+    levels
+        .iter()
+        .filter(|(_, level)| **level != LevelFilter::Off)
+        .map(|(module, level)| {
+            let prefix = format!("{}::", module);
+            LogRule {
+                module: module.clone(),
+                level: *level,
+                ignore: levels
+                    .keys()
+                    .filter(|other| other.starts_with(&prefix))
+                    .cloned()
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Sends entries to journald using its native protocol
+/// keeps levels and targets as structured fields instead of text
+struct JournaldLogger {
+    rule: LogRule,
+    socket: Arc<UnixDatagram>,
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.rule.level && self.rule.matches(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let entry = journal_entry(record);
+        if let Err(error) = self.socket.send_to(&entry, JOURNALD_SOCKET) {
+            eprintln!("Failed to write to journald {}", error);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for JournaldLogger {
+    fn level(&self) -> LevelFilter {
+        self.rule.level
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let mut entry = vec![];
+    add_journal_field(&mut entry, "PRIORITY", journal_priority(record.level()));
+    add_journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+    add_journal_field(&mut entry, "SYSLOG_IDENTIFIER", CRATE_NAME);
+    add_journal_field(&mut entry, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        add_journal_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        add_journal_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    entry
+}
+
+/// Values containing newlines are sent length prefixed
+fn add_journal_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// syslog priorities
+fn journal_priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+/// Log file shared by the loggers of each module
+/// every handle buffers until the end of a line so entries from different loggers don't interleave
+#[derive(Clone)]
+struct RotatingFile {
+    buffer: Vec<u8>,
+    file: Arc<Mutex<LogFile>>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    max_size_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(config: &LogFileConfig) -> anyhow::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create log directory {:?}", dir))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .with_context(|| format!("Failed to open log file {:?}", config.path))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            buffer: vec![],
+            file: Arc::new(Mutex::new(LogFile {
+                path: config.path.clone(),
+                max_size_bytes: config.max_size_bytes,
+                max_files: config.max_files,
+                file,
+                size,
+            })),
+        })
+    }
+
+    fn write_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut log_file = self.file.lock().unwrap_or_else(|error| error.into_inner());
+        if log_file.size > 0 && log_file.size + self.buffer.len() as u64 > log_file.max_size_bytes {
+            log_file.rotate()?;
+        }
+        log_file.file.write_all(&self.buffer)?;
+        log_file.size += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if buf.ends_with(b"\n") {
+            self.write_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        self.file
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .file
+            .flush()
+    }
+}
+
+impl LogFile {
+    /// Shift path.1, path.2... up by one, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() {
+                fs::rename(from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
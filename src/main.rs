@@ -1,42 +1,210 @@
+mod config_check;
+mod config_reload;
 mod configuration;
+mod discord_connection;
+mod house_mode;
+mod logging;
+mod metered_channel;
+mod metrics;
 mod mqtt;
+mod notifications;
+mod outbound_queue;
+mod run_history;
+mod template;
 
-use crate::{configuration::get_configuration, mqtt::start_mqtt_service};
+use crate::{
+    config_check::check_configuration,
+    config_reload::watch_configuration,
+    configuration::{
+        check_configuration_sources, get_configuration, get_logging_configuration, AppConfig,
+        ChannelRef, LoggingConfig, MentionTargets,
+    },
+    discord_connection::DiscordConnectionTracker,
+    house_mode::{HouseMode, HouseModeStore},
+    logging::setup_logging,
+    metered_channel::{metered_channel, MeteredSender},
+    metrics::{metrics, start_metrics_server, ScrapeSources},
+    mqtt::{start_mqtt_service, MqttConnectionTracker},
+    notifications::allow_only,
+    outbound_queue::{OutboundMessage, OutboundQueue},
+    run_history::{format_uptime, start_run},
+};
 use log::*;
 use serenity::{
     async_trait,
+    client::bridge::gateway::event::ShardStageUpdateEvent,
     client::{Context, EventHandler},
-    model::{channel::Message, gateway::Ready, id::ChannelId},
+    model::{
+        channel::Message,
+        event::ResumedEvent,
+        gateway::Ready,
+        id::{ChannelId, GuildId},
+    },
     prelude::*,
 };
-use simplelog::*;
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 use structopt::StructOpt;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+const MODE_COMMAND: &str = "!mode";
+const STATUS_COMMAND: &str = "!status";
+/// Discord messages waiting to be published over MQTT
+/// Messages are dropped if the broker can't keep up
+const DISCORD_MESSAGE_QUEUE_SIZE: usize = 100;
+const MQTT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long shutdown waits for MQTT messages that were already received
+const MQTT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
 struct DiscordMessageHandler {
-    message_sender: UnboundedSender<Message>,
+    message_sender: MeteredSender<Message>,
+    house_mode: Arc<HouseModeStore>,
+    mqtt_connection: Arc<MqttConnectionTracker>,
+    discord_connection: Arc<DiscordConnectionTracker>,
+    config_updates: watch::Receiver<AppConfig>,
+    /// Taken by the first ready event so reconnects don't announce again
+    startup_announcement: Mutex<Option<(ChannelId, String)>>,
 }
 
 impl DiscordMessageHandler {
-    pub fn new(message_sender: UnboundedSender<Message>) -> Self {
-        Self { message_sender }
+    pub fn new(
+        message_sender: MeteredSender<Message>,
+        house_mode: Arc<HouseModeStore>,
+        mqtt_connection: Arc<MqttConnectionTracker>,
+        discord_connection: Arc<DiscordConnectionTracker>,
+        config_updates: watch::Receiver<AppConfig>,
+        startup_announcement: Option<(ChannelId, String)>,
+    ) -> Self {
+        Self {
+            message_sender,
+            house_mode,
+            mqtt_connection,
+            discord_connection,
+            config_updates,
+            startup_announcement: Mutex::new(startup_announcement),
+        }
+    }
+
+    fn handle_status_command(&self) -> String {
+        let mqtt = self.mqtt_connection.get();
+        let mut status = format!(
+            "House mode is {}\nMQTT is {} ({} reconnects)",
+            self.house_mode.get(),
+            if mqtt.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            mqtt.reconnects
+        );
+        if let Some(error) = mqtt.last_error.filter(|_| !mqtt.connected) {
+            status.push_str(&format!("\nLast MQTT error: {error}"));
+        }
+        status
+    }
+
+    /// `!mode` shows the current house mode and `!mode <mode>` changes it
+    fn handle_mode_command(&self, msg: &Message, arguments: &str) -> String {
+        if arguments.is_empty() {
+            return format!("House mode is {}", self.house_mode.get());
+        }
+        let roles: Vec<_> = msg
+            .member
+            .iter()
+            .flat_map(|member| member.roles.iter().map(|role| role.0))
+            .collect();
+        let allowed = self.config_updates.borrow().home.can_change_mode(
+            msg.author.id.0,
+            &roles,
+            msg.channel_id.0,
+        );
+        if !allowed {
+            warn!(
+                "{} tried to change house mode in {}",
+                msg.author.name, msg.channel_id
+            );
+            return "You are not allowed to change the house mode".to_owned();
+        }
+        match arguments
+            .parse::<HouseMode>()
+            .and_then(|mode| self.house_mode.set(mode).map(|_| mode))
+        {
+            Ok(mode) => format!("House mode set to {mode}"),
+            Err(e) => {
+                error!("Failed setting house mode {e}");
+                format!("Failed setting house mode: {e}")
+            }
+        }
     }
 }
 
 #[async_trait]
 impl EventHandler for DiscordMessageHandler {
     async fn message(&self, ctx: Context, msg: Message) {
-        if msg.content.to_ascii_lowercase() == "ping" {
+        if msg.content.eq_ignore_ascii_case("ping") {
             if let Err(why) = msg.channel_id.say(&ctx.http, "Pong!").await {
                 error!("Error sending message: {:?}", why);
             }
         }
-        self.message_sender.send(msg).unwrap();
+        if !msg.author.bot {
+            let reply = if let Some(arguments) = command_arguments(&msg.content, MODE_COMMAND) {
+                Some(self.handle_mode_command(&msg, arguments))
+            } else if msg.content.trim() == STATUS_COMMAND {
+                Some(self.handle_status_command())
+            } else {
+                None
+            };
+            if let Some(reply) = reply {
+                // replies can echo user text so they never ping anyone
+                let sent = msg
+                    .channel_id
+                    .send_message(&ctx.http, |message| {
+                        message.content(reply).allowed_mentions(|allowed| {
+                            allow_only(allowed, &MentionTargets::default())
+                        })
+                    })
+                    .await;
+                if let Err(why) = sent {
+                    error!("Error sending message: {:?}", why);
+                }
+            }
+        }
+        metrics()
+            .discord_messages_received
+            .with_label_values(&[&msg.channel_id.to_string()])
+            .inc();
+        self.message_sender.send_or_drop(msg);
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
+        self.discord_connection.ready(ready.user.name, ready.shard);
+        self.discord_connection.guilds_available(ready.guilds.len());
+        if let Some((channel, announcement)) = self.startup_announcement.lock().await.take() {
+            if let Err(why) = channel.say(&ctx.http, announcement).await {
+                error!("Failed sending startup announcement: {:?}", why);
+            }
+        }
+    }
+
+    async fn resume(&self, _: Context, _: ResumedEvent) {
+        info!("Discord connection resumed");
+        self.discord_connection.resumed();
+    }
+
+    async fn shard_stage_update(&self, _: Context, event: ShardStageUpdateEvent) {
+        info!(
+            "Discord shard {} went from {} to {}",
+            event.shard_id, event.old, event.new
+        );
+        self.discord_connection.stage_changed(event.new);
+    }
+
+    async fn cache_ready(&self, _: Context, guilds: Vec<GuildId>) {
+        info!("Discord cache ready with {} guilds", guilds.len());
+        self.discord_connection.guilds_available(guilds.len());
     }
 }
 
@@ -49,58 +217,275 @@ impl EventHandler for DiscordMessageHandler {
 struct Opts {
     #[structopt(long)]
     config: Option<PathBuf>,
+    /// Log level for our own logs, overrides logging.level
+    #[structopt(long)]
+    log_level: Option<LevelFilter>,
+    /// Log level for a module such as serenity or rumqttc, as module=level
+    #[structopt(long = "log-module", parse(try_from_str = parse_log_module))]
+    log_modules: Vec<(String, LevelFilter)>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Check the configuration for problems and exit
+    CheckConfig,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    setup_logging();
     let opts = Opts::from_args();
-    let app_config = get_configuration(opts.config)?;
+    if let Some(Command::CheckConfig) = opts.command {
+        return check_config(opts.config);
+    }
+    // logging comes first so loading the rest of the configuration gets logged
+    let mut logging = get_logging_configuration(opts.config.clone()).unwrap_or_else(|e| {
+        eprintln!("Using default logging, failed reading logging configuration {e:#}");
+        LoggingConfig::default()
+    });
+    if let Some(level) = opts.log_level {
+        logging.level = level;
+    }
+    logging.modules.extend(opts.log_modules);
+    setup_logging(&logging)?;
+    let app_config = get_configuration(opts.config.clone())?;
+    let config_updates = watch_configuration(opts.config, app_config.clone())?;
 
     // discord time
     let intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
 
-    let (sender, receiver) = unbounded_channel();
+    let (sender, receiver) = metered_channel(
+        "Discord message",
+        "discord_messages",
+        DISCORD_MESSAGE_QUEUE_SIZE,
+    );
+
+    let house_mode = HouseModeStore::load(app_config.home.state_file.clone());
+    let mqtt_connection = MqttConnectionTracker::new();
+    let discord_connection = DiscordConnectionTracker::new();
+    let started_at = chrono::Utc::now();
+    let previous_uptime = start_run(app_config.home.run_history_file.clone());
+    // a bad alias or template shouldn't keep us from starting
+    let startup_announcement =
+        startup_announcement(&app_config, previous_uptime).unwrap_or_else(|e| {
+            warn!("Skipping startup announcement {e}");
+            None
+        });
 
-    let mut client = Client::builder(&app_config.discord.token, intents)
-        .event_handler(DiscordMessageHandler::new(sender))
+    let mut client = Client::builder(app_config.discord.token.expose(), intents)
+        .event_handler(DiscordMessageHandler::new(
+            sender.clone(),
+            house_mode.clone(),
+            mqtt_connection.clone(),
+            discord_connection.clone(),
+            config_updates.clone(),
+            startup_announcement,
+        ))
         .await
         .expect("Err creating client");
 
     let http = client.cache_and_http.http.clone();
+    let shard_manager = client.shard_manager.clone();
+    discord_connection.start_latency_task(shard_manager.clone());
 
-    let channel = ChannelId(app_config.home.notification_discord_channel);
-    channel.say(&http, "WholeSumBoi is online").await?;
+    let outbound_queue = OutboundQueue::open(app_config.outbound_queue.clone())?;
+    outbound_queue.start(http.clone());
 
-    start_mqtt_service(app_config, http, receiver)?;
+    let mut mqtt_service = start_mqtt_service(
+        config_updates.clone(),
+        http,
+        receiver,
+        house_mode,
+        outbound_queue.clone(),
+        mqtt_connection.clone(),
+        discord_connection.clone(),
+    )?;
+
+    if app_config.metrics.enabled {
+        let sources = ScrapeSources {
+            channels: vec![Box::new(sender), mqtt_service.message_queue()],
+            outbound_queue: outbound_queue.clone(),
+            mqtt_connection,
+            discord_connection,
+        };
+        start_metrics_server(&app_config.metrics, sources)?;
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
 
     info!("Starting discord client");
-    if let Err(why) = client.start().await {
-        error!("Client error: {:?}", why);
+    // exit with an error when either side stops so that systemd restarts us
+    tokio::select! {
+        result = client.start() => {
+            if let Err(why) = result {
+                error!("Client error: {:?}", why);
+                return Err(why.into());
+            }
+            anyhow::bail!("Discord client stopped");
+        }
+        error = mqtt_service.wait_for_failure() => {
+            error!("MQTT service error: {:?}", error);
+            return Err(error);
+        }
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+    }
+
+    mqtt_service.stop_input(MQTT_DRAIN_TIMEOUT).await;
+    // announce using the latest reloaded templates
+    let app_config = config_updates.borrow().clone();
+
+    // a bad announcement shouldn't stop us from shutting down cleanly
+    let queued = match shutdown_announcement(&app_config, started_at) {
+        Ok(Some((channel, content))) => {
+            outbound_queue
+                .enqueue(OutboundMessage::new(channel, content))
+                .await
+        }
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = queued {
+        error!("Failed queueing shutdown announcement {e}");
     }
+    let drain_timeout = Duration::from_secs(app_config.outbound_queue.drain_timeout_secs);
+    match outbound_queue.drain(drain_timeout).await {
+        Ok(0) => info!("Outbound queue drained"),
+        Ok(pending) => warn!("Shutting down with {pending} messages still queued"),
+        Err(e) => error!("Failed draining outbound queue {e}"),
+    }
+
+    shard_manager.lock().await.shutdown_all().await;
+    mqtt_service.shutdown(MQTT_DISCONNECT_TIMEOUT).await;
+    info!("Shutdown complete");
     Ok(())
 }
 
-fn setup_logging() {
-    // only allow logs from our crate
-    // serenity is spammy
-    let config = ConfigBuilder::new()
-        .add_filter_allow_str("whole_sum_boi_discord")
-        .build();
-
-    if TermLogger::init(
-        LevelFilter::Info,
-        config.clone(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
+/// Print every configuration problem, fails if there are any
+fn check_config(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let (app_config, mut problems) = check_configuration_sources(config_path);
+    if let Some(app_config) = app_config {
+        problems.extend(check_configuration(&app_config));
+    }
+    if problems.is_empty() {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{problem}");
+    }
+    anyhow::bail!("Found {} configuration problems", problems.len())
+}
+
+fn startup_announcement(
+    app_config: &AppConfig,
+    previous_uptime: Option<chrono::Duration>,
+) -> anyhow::Result<Option<(ChannelId, String)>> {
+    let config = &app_config.home.startup_announcement;
+    if !config.enabled {
+        return Ok(None);
+    }
+    let previous_uptime = previous_uptime
+        .map(format_uptime)
+        .unwrap_or_else(|| String::from("unknown"));
+    announcement(
+        app_config,
+        &config.channel,
+        &config.template,
+        ("previous_uptime", previous_uptime),
     )
-    .is_err()
-    {
-        eprintln!("Failed to create term logger");
-        if SimpleLogger::init(LevelFilter::Info, config).is_err() {
-            eprintln!("Failed to create simple logger");
-        }
+    .map(Some)
+}
+
+fn shutdown_announcement(
+    app_config: &AppConfig,
+    started_at: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<Option<(ChannelId, String)>> {
+    let config = &app_config.home.shutdown_announcement;
+    if !config.enabled {
+        return Ok(None);
+    }
+    let uptime = format_uptime(chrono::Utc::now() - started_at);
+    announcement(
+        app_config,
+        &config.channel,
+        &config.template,
+        ("uptime", uptime),
+    )
+    .map(Some)
+}
+
+/// Render an announcement template with the values common to all announcements
+fn announcement(
+    app_config: &AppConfig,
+    channel: &Option<ChannelRef>,
+    template: &str,
+    extra_value: (&'static str, String),
+) -> anyhow::Result<(ChannelId, String)> {
+    let channel = match channel {
+        Some(channel) => channel
+            .resolve(&app_config.home.channel_aliases)
+            .ok_or_else(|| anyhow::anyhow!("Unknown announcement channel {channel:?}"))?,
+        None => app_config.home.notification_discord_channel,
+    };
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|host| host.trim().to_owned())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| String::from("unknown"));
+    let config_summary = format!(
+        "MQTT {}:{} on {}, {} quiet hours, {} device rules",
+        app_config.mqtt.broker_host,
+        app_config.mqtt.broker_port,
+        app_config.mqtt.base_route,
+        app_config.home.quiet_hours.len(),
+        app_config.home.devices.len()
+    );
+    let values = HashMap::from([
+        ("version", env!("CARGO_PKG_VERSION").to_owned()),
+        ("host", host),
+        ("config_summary", config_summary),
+        extra_value,
+    ]);
+    Ok((ChannelId(channel), template::render(template, &values)))
+}
+
+/// Arguments of a `!command`, None if the message is something else such as `!modes`
+fn command_arguments<'a>(content: &'a str, command: &str) -> Option<&'a str> {
+    let rest = content.trim().strip_prefix(command)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+fn parse_log_module(value: &str) -> anyhow::Result<(String, LevelFilter)> {
+    let (module, level) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected module=level"))?;
+    Ok((module.to_owned(), level.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_arguments_need_a_word_boundary() {
+        assert_eq!(command_arguments("!mode", MODE_COMMAND), Some(""));
+        assert_eq!(
+            command_arguments("  !mode away ", MODE_COMMAND),
+            Some("away")
+        );
+        assert_eq!(
+            command_arguments("!mode\tnight", MODE_COMMAND),
+            Some("night")
+        );
+        assert_eq!(command_arguments("!modes", MODE_COMMAND), None);
+        assert_eq!(command_arguments("!modefoo away", MODE_COMMAND), None);
+        assert_eq!(command_arguments("hello !mode away", MODE_COMMAND), None);
     }
 }
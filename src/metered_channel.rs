@@ -0,0 +1,96 @@
+use crate::metrics::metrics;
+use log::*;
+use prometheus::IntCounter;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Bounded channel sender that counts what it sends and drops
+pub struct MeteredSender<T> {
+    name: &'static str,
+    label: &'static str,
+    sender: mpsc::Sender<T>,
+    sent: IntCounter,
+    dropped: IntCounter,
+}
+
+impl<T> Clone for MeteredSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            label: self.label,
+            sender: self.sender.clone(),
+            sent: self.sent.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+/// `label` names the channel in metrics, `name` in logs
+pub fn metered_channel<T>(
+    name: &'static str,
+    label: &'static str,
+    capacity: usize,
+) -> (MeteredSender<T>, mpsc::Receiver<T>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let counters = &metrics().channel_messages;
+    let sender = MeteredSender {
+        name,
+        label,
+        sender,
+        sent: counters.with_label_values(&[label, "sent"]),
+        dropped: counters.with_label_values(&[label, "dropped"]),
+    };
+    (sender, receiver)
+}
+
+impl<T> MeteredSender<T> {
+    /// Send without waiting, the message is dropped if the channel is full or closed
+    pub fn send_or_drop(&self, value: T) -> bool {
+        match self.sender.try_send(value) {
+            Ok(()) => {
+                self.sent.inc();
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.dropped.inc();
+                warn!(
+                    "{} queue is full, dropped {} messages so far",
+                    self.name,
+                    self.dropped.get()
+                );
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.dropped.inc();
+                error!("{} queue is closed", self.name);
+                false
+            }
+        }
+    }
+
+    /// Wait for space in the channel
+    pub async fn send(&self, value: T) -> anyhow::Result<()> {
+        self.sender
+            .send(value)
+            .await
+            .map_err(|_| anyhow::anyhow!("{} queue is closed", self.name))?;
+        self.sent.inc();
+        Ok(())
+    }
+}
+
+/// Channel whose depth is sampled for metrics, whatever it carries
+pub trait ChannelDepth: Send + Sync {
+    fn label(&self) -> &'static str;
+    /// Messages waiting to be received
+    fn depth(&self) -> usize;
+}
+
+impl<T: Send> ChannelDepth for MeteredSender<T> {
+    fn label(&self) -> &'static str {
+        self.label
+    }
+
+    fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+}
@@ -0,0 +1,242 @@
+use crate::{
+    configuration::MetricsConfig, discord_connection::DiscordConnectionTracker,
+    metered_channel::ChannelDepth, mqtt::MqttConnectionTracker, outbound_queue::OutboundQueue,
+};
+use anyhow::Context;
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::*;
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::{
+    convert::Infallible,
+    future::Future,
+    sync::{Arc, OnceLock},
+};
+
+const METRICS_PATH: &str = "/metrics";
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Metrics are recorded whether or not the endpoint is enabled
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("Invalid metric definitions"))
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub discord_messages_received: IntCounterVec,
+    pub mqtt_messages: IntCounterVec,
+    pub handler_errors: IntCounterVec,
+    pub discord_request_duration: HistogramVec,
+    pub rate_limit_hits: IntCounterVec,
+    pub reconnects: IntCounterVec,
+    pub sensor_events: IntCounterVec,
+    pub channel_messages: IntCounterVec,
+    discord_gateway_latency: Gauge,
+    queue_depth: IntGaugeVec,
+    connected: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("whole_sum_boi".to_owned()), None)?;
+        let metrics = Self {
+            discord_messages_received: IntCounterVec::new(
+                Opts::new(
+                    "discord_messages_received_total",
+                    "Discord messages received per channel",
+                ),
+                &["channel"],
+            )?,
+            mqtt_messages: IntCounterVec::new(
+                Opts::new("mqtt_messages_total", "MQTT messages handled per route"),
+                &["route"],
+            )?,
+            handler_errors: IntCounterVec::new(
+                Opts::new("handler_errors_total", "Route handler failures by kind"),
+                &["route", "kind"],
+            )?,
+            discord_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "discord_request_duration_seconds",
+                    "Duration of discord API requests",
+                ),
+                &["operation"],
+            )?,
+            rate_limit_hits: IntCounterVec::new(
+                Opts::new(
+                    "rate_limit_hits_total",
+                    "Messages held back by our rate limit or rejected by discord",
+                ),
+                &["limiter"],
+            )?,
+            reconnects: IntCounterVec::new(
+                Opts::new("reconnects_total", "Reconnects after the first connection"),
+                &["connection"],
+            )?,
+            sensor_events: IntCounterVec::new(
+                Opts::new(
+                    "sensor_events_total",
+                    "Sensor events per configured device, others are counted as other",
+                ),
+                &["device"],
+            )?,
+            channel_messages: IntCounterVec::new(
+                Opts::new(
+                    "channel_messages_total",
+                    "Messages sent or dropped on internal channels",
+                ),
+                &["channel", "result"],
+            )?,
+            discord_gateway_latency: Gauge::new(
+                "discord_gateway_latency_seconds",
+                "Heartbeat latency of the discord gateway",
+            )?,
+            queue_depth: IntGaugeVec::new(
+                Opts::new("queue_depth", "Messages waiting in each queue"),
+                &["queue"],
+            )?,
+            connected: IntGaugeVec::new(
+                Opts::new("connected", "Whether each connection is up"),
+                &["connection"],
+            )?,
+            registry,
+        };
+        metrics.register()?;
+        Ok(metrics)
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry
+            .register(Box::new(self.discord_messages_received.clone()))?;
+        self.registry
+            .register(Box::new(self.mqtt_messages.clone()))?;
+        self.registry
+            .register(Box::new(self.handler_errors.clone()))?;
+        self.registry
+            .register(Box::new(self.discord_request_duration.clone()))?;
+        self.registry
+            .register(Box::new(self.rate_limit_hits.clone()))?;
+        self.registry.register(Box::new(self.reconnects.clone()))?;
+        self.registry
+            .register(Box::new(self.sensor_events.clone()))?;
+        self.registry
+            .register(Box::new(self.channel_messages.clone()))?;
+        self.registry
+            .register(Box::new(self.discord_gateway_latency.clone()))?;
+        self.registry.register(Box::new(self.queue_depth.clone()))?;
+        self.registry.register(Box::new(self.connected.clone()))?;
+        Ok(())
+    }
+}
+
+/// Time a discord API request
+pub async fn time_discord_request<T>(operation: &str, request: impl Future<Output = T>) -> T {
+    let timer = metrics()
+        .discord_request_duration
+        .with_label_values(&[operation])
+        .start_timer();
+    let result = request.await;
+    timer.observe_duration();
+    result
+}
+
+/// State sampled on every scrape instead of being recorded as it changes
+pub struct ScrapeSources {
+    pub channels: Vec<Box<dyn ChannelDepth>>,
+    pub outbound_queue: Arc<OutboundQueue>,
+    pub mqtt_connection: Arc<MqttConnectionTracker>,
+    pub discord_connection: Arc<DiscordConnectionTracker>,
+}
+
+impl ScrapeSources {
+    async fn sample(&self) {
+        let metrics = metrics();
+        for channel in &self.channels {
+            metrics
+                .queue_depth
+                .with_label_values(&[channel.label()])
+                .set(channel.depth() as i64);
+        }
+        match self.outbound_queue.len().await {
+            Ok(pending) => metrics
+                .queue_depth
+                .with_label_values(&["outbound"])
+                .set(pending as i64),
+            Err(e) => warn!("Failed reading outbound queue length {e}"),
+        }
+
+        let mqtt = self.mqtt_connection.get();
+        metrics
+            .connected
+            .with_label_values(&["mqtt"])
+            .set(mqtt.connected as i64);
+        let discord = self.discord_connection.get();
+        metrics
+            .connected
+            .with_label_values(&["discord"])
+            .set(discord.connected as i64);
+        if let Some(latency_ms) = discord.latency_ms {
+            metrics
+                .discord_gateway_latency
+                .set(latency_ms as f64 / 1000.0);
+        }
+    }
+}
+
+pub fn start_metrics_server(config: &MetricsConfig, sources: ScrapeSources) -> anyhow::Result<()> {
+    let sources = Arc::new(sources);
+    let make_service = make_service_fn(move |_| {
+        let sources = sources.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let sources = sources.clone();
+                async move { Ok::<_, Infallible>(respond(&request, &sources).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&config.listen_address)
+        .with_context(|| format!("Failed to bind metrics listener {}", config.listen_address))?
+        .serve(make_service);
+    info!(
+        "Serving metrics on http://{}{METRICS_PATH}",
+        config.listen_address
+    );
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Metrics server failed {e}");
+        }
+    });
+    Ok(())
+}
+
+async fn respond(request: &Request<Body>, sources: &ScrapeSources) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != METRICS_PATH {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    sources.sample().await;
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&metrics().registry.gather(), &mut buffer) {
+        error!("Failed to encode metrics {e}");
+        return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let mut response = Response::new(Body::from(buffer));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(prometheus::TEXT_FORMAT),
+    );
+    response
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
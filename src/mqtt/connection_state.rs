@@ -0,0 +1,55 @@
+use crate::metrics::metrics;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MqttConnectionState {
+    pub connected: bool,
+    /// Number of times we reconnected after the first connection
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+    pub last_change: Option<DateTime<Utc>>,
+}
+
+/// Tracks whether we are connected to the MQTT broker
+pub struct MqttConnectionTracker {
+    sender: watch::Sender<MqttConnectionState>,
+}
+
+impl MqttConnectionTracker {
+    pub fn new() -> Arc<Self> {
+        let (sender, _) = watch::channel(MqttConnectionState::default());
+        Arc::new(Self { sender })
+    }
+
+    pub fn get(&self) -> MqttConnectionState {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<MqttConnectionState> {
+        self.sender.subscribe()
+    }
+
+    pub(super) fn connected(&self) {
+        self.sender.send_modify(|state| {
+            if state.last_change.is_some() {
+                state.reconnects += 1;
+                metrics().reconnects.with_label_values(&["mqtt"]).inc();
+            }
+            state.connected = true;
+            state.last_change = Some(Utc::now());
+        });
+    }
+
+    pub(super) fn disconnected(&self, error: String) {
+        self.sender.send_modify(|state| {
+            if state.connected {
+                state.last_change = Some(Utc::now());
+            }
+            state.connected = false;
+            state.last_error = Some(error);
+        });
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{
+    configuration::PublishConfig,
+    metrics::metrics,
+    outbound_queue::{DropReason, DroppedMessage},
+};
+use async_trait::async_trait;
+use log::*;
+use mqtt_router::{RouteHandler, RouterError};
+use rumqttc::AsyncClient;
+use serde::Serialize;
+use thiserror::Error;
+
+const PAYLOAD_EXCERPT_LENGTH: usize = 256;
+/// Reported as the topic of messages dropped by the outbound queue
+const OUTBOUND_QUEUE_TOPIC: &str = "outbound_queue";
+
+/// Errors produced by our route handlers
+#[derive(Debug, Error)]
+pub enum HandlerError {
+    #[error("Failed to parse payload {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid payload {0}")]
+    InvalidPayload(String),
+    #[error("Discord error {0}")]
+    Discord(Box<serenity::Error>),
+    #[error("Not permitted {0}")]
+    Permission(String),
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<serenity::Error> for HandlerError {
+    fn from(error: serenity::Error) -> Self {
+        HandlerError::Discord(Box::new(error))
+    }
+}
+
+impl From<anyhow::Error> for HandlerError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<serenity::Error>() {
+            Ok(error) => HandlerError::from(error),
+            Err(error) => HandlerError::Other(error),
+        }
+    }
+}
+
+impl From<HandlerError> for RouterError {
+    fn from(error: HandlerError) -> Self {
+        RouterError::HandlerError(error.into())
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Parse,
+    Discord,
+    Permission,
+    Io,
+    Internal,
+    RateLimit,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Parse => "parse",
+            ErrorKind::Discord => "discord",
+            ErrorKind::Permission => "permission",
+            ErrorKind::Io => "io",
+            ErrorKind::Internal => "internal",
+            ErrorKind::RateLimit => "rate_limit",
+        }
+    }
+}
+
+impl HandlerError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            HandlerError::Parse(_) | HandlerError::InvalidPayload(_) => ErrorKind::Parse,
+            HandlerError::Discord(_) => ErrorKind::Discord,
+            HandlerError::Permission(_) => ErrorKind::Permission,
+            HandlerError::Io(_) => ErrorKind::Io,
+            HandlerError::Other(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Reports handler failures to MQTT so producers can see them
+#[derive(Clone)]
+pub struct ErrorPublisher {
+    client: AsyncClient,
+    topic: String,
+    publish_config: PublishConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorReport<'a> {
+    topic: &'a str,
+    handler: &'a str,
+    kind: ErrorKind,
+    error: String,
+    payload_excerpt: String,
+    request_id: Option<serde_json::Value>,
+}
+
+impl ErrorPublisher {
+    pub fn new(client: AsyncClient, base_topic: &str, publish_config: PublishConfig) -> Self {
+        Self {
+            client,
+            topic: format!("{base_topic}/errors/v1"),
+            publish_config,
+        }
+    }
+
+    /// Wrap a handler so that its failures get published
+    pub fn wrap<H: RouteHandler + Send>(&self, handler: Box<H>) -> Box<ReportErrors<H>> {
+        let handler_name = std::any::type_name::<H>()
+            .rsplit("::")
+            .next()
+            .unwrap_or("unknown");
+        Box::new(ReportErrors {
+            handler_name,
+            inner: handler,
+            errors: self.clone(),
+        })
+    }
+
+    /// Report messages the outbound queue gave up on
+    pub async fn publish_dropped(&self, dropped: &DroppedMessage) {
+        let kind = match dropped.reason {
+            DropReason::RateLimited => ErrorKind::RateLimit,
+            DropReason::DeadLetter => ErrorKind::Discord,
+        };
+        // messages from MQTT are reported against the topic they came from
+        let origin = dropped.message.origin.as_ref();
+        let report = ErrorReport {
+            topic: origin.map_or(OUTBOUND_QUEUE_TOPIC, |origin| &origin.topic),
+            handler: origin.map_or("OutboundQueue", |origin| &origin.handler),
+            kind,
+            error: format!(
+                "Dropped message to channel {} {}",
+                dropped.message.channel_id, dropped.error
+            ),
+            payload_excerpt: payload_excerpt(dropped.message.content.as_bytes()),
+            request_id: origin.and_then(|origin| origin.request_id.clone()),
+        };
+        self.publish(&report).await;
+    }
+
+    async fn publish(&self, report: &ErrorReport<'_>) {
+        match serde_json::to_string(report) {
+            Ok(json) => {
+                if let Err(e) = self
+                    .client
+                    .publish(
+                        &self.topic,
+                        self.publish_config.qos.into(),
+                        self.publish_config.retain,
+                        json,
+                    )
+                    .await
+                {
+                    error!("Failed sending mqtt message {e}");
+                }
+            }
+            Err(e) => error!("Failed to serialize error report {e}"),
+        }
+    }
+}
+
+pub struct ReportErrors<H> {
+    handler_name: &'static str,
+    inner: Box<H>,
+    errors: ErrorPublisher,
+}
+
+#[async_trait]
+impl<H: RouteHandler + Send> RouteHandler for ReportErrors<H> {
+    async fn call(&mut self, topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+        metrics()
+            .mqtt_messages
+            .with_label_values(&[self.handler_name])
+            .inc();
+        let result = self.inner.call(topic, content).await;
+        if let Err(error) = &result {
+            let (kind, message) = match error {
+                RouterError::HandlerError(inner) => match inner.downcast_ref::<HandlerError>() {
+                    Some(handler_error) => (handler_error.kind(), handler_error.to_string()),
+                    None => (ErrorKind::Internal, inner.to_string()),
+                },
+                other => (ErrorKind::Internal, other.to_string()),
+            };
+            error!(
+                "Handler {} failed on {topic} with {kind:?} error {message}",
+                self.handler_name
+            );
+            metrics()
+                .handler_errors
+                .with_label_values(&[self.handler_name, kind.as_str()])
+                .inc();
+            let report = ErrorReport {
+                topic,
+                handler: self.handler_name,
+                kind,
+                error: message,
+                payload_excerpt: payload_excerpt(content),
+                request_id: request_id(content),
+            };
+            self.errors.publish(&report).await;
+        }
+        result
+    }
+}
+
+fn payload_excerpt(content: &[u8]) -> String {
+    let excerpt = String::from_utf8_lossy(&content[..content.len().min(PAYLOAD_EXCERPT_LENGTH)]);
+    excerpt.into_owned()
+}
+
+/// Producers can tag payloads with a `request_id` to match up errors
+fn request_id(content: &[u8]) -> Option<serde_json::Value> {
+    let payload: serde_json::Value = serde_json::from_slice(content).ok()?;
+    payload.get("request_id").cloned()
+}
@@ -0,0 +1,254 @@
+use super::errors::HandlerError;
+use crate::configuration::{AppConfig, PublishConfig};
+use anyhow::Context;
+use async_trait::async_trait;
+use log::*;
+use mqtt_router::{RouteHandler, RouterError};
+use rumqttc::AsyncClient;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Discovery config for a single Home Assistant entity
+struct Entity {
+    component: &'static str,
+    object_id: String,
+    config: Value,
+}
+
+/// Publishes Home Assistant discovery configs for the bot's entities
+pub struct HomeAssistantDiscovery {
+    client: AsyncClient,
+    discovery_prefix: String,
+    node_id: String,
+    entities: Vec<Entity>,
+    publish_config: PublishConfig,
+}
+
+impl HomeAssistantDiscovery {
+    pub fn new(client: AsyncClient, app_config: &AppConfig) -> anyhow::Result<Arc<Self>> {
+        let config = &app_config.mqtt.home_assistant;
+        let base_topic = &app_config.mqtt.base_route;
+        let aliases = &app_config.home.channel_aliases;
+        let node_id = slug(&app_config.mqtt.client_id);
+        let status_topic = format!("{base_topic}/status");
+        let say_channel_topic = format!("{base_topic}/say_channel");
+
+        let device = json!({
+            "identifiers": [node_id],
+            "name": "WholeSumBoi",
+            "model": "Discord bot",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let availability = json!({
+            "availability_topic": status_topic,
+            "availability_template": "{{ value_json.state }}",
+        });
+        let entity = |component, object_id: &str, mut config: Value, available: bool| {
+            config["unique_id"] = json!(format!("{node_id}_{object_id}"));
+            config["device"] = device.clone();
+            if available {
+                merge(&mut config, &availability);
+            }
+            Entity {
+                component,
+                object_id: object_id.to_owned(),
+                config,
+            }
+        };
+
+        let notify_channel = match &config.notify_channel {
+            Some(channel) => channel
+                .resolve(aliases)
+                .with_context(|| format!("Unknown Home Assistant notify channel {channel:?}"))?,
+            None => app_config.home.notification_discord_channel,
+        };
+
+        let mut entities = vec![
+            entity(
+                "notify",
+                "discord",
+                json!({
+                    "name": "Discord",
+                    "command_topic": say_channel_topic,
+                    "command_template": format!(
+                        "{{\"channel_id\": {notify_channel}, \"content\": {{{{ message | tojson }}}}}}"
+                    ),
+                }),
+                true,
+            ),
+            entity(
+                "binary_sensor",
+                "discord_connected",
+                json!({
+                    "name": "Discord connected",
+                    "device_class": "connectivity",
+                    "state_topic": format!("{base_topic}/discord_status/v1"),
+                    "value_template": "{{ 'ON' if value_json.connected else 'OFF' }}",
+                }),
+                true,
+            ),
+            // stays available so it shows off when our last will is published
+            entity(
+                "binary_sensor",
+                "mqtt_connected",
+                json!({
+                    "name": "MQTT connected",
+                    "device_class": "connectivity",
+                    "state_topic": status_topic,
+                    "value_template": "{{ 'ON' if value_json.state == 'online' else 'OFF' }}",
+                }),
+                false,
+            ),
+            entity(
+                "sensor",
+                "last_message",
+                json!({
+                    "name": "Last message",
+                    "icon": "mdi:message-text",
+                    "state_topic": format!("{base_topic}/new_message/v1"),
+                    // sensor states are limited to 255 characters
+                    "value_template": "{{ value_json.content[:255] }}",
+                    "json_attributes_topic": format!("{base_topic}/new_message/v1"),
+                    "json_attributes_template":
+                        "{{ {'author_id': value_json.author_id, 'channel_id': value_json.channel_id} | tojson }}",
+                }),
+                true,
+            ),
+        ];
+
+        for quick_message in &config.quick_messages {
+            let channel_id = quick_message.channel.resolve(aliases).with_context(|| {
+                format!(
+                    "Unknown channel {:?} for quick message {}",
+                    quick_message.channel, quick_message.name
+                )
+            })?;
+            let payload = json!({ "channel_id": channel_id, "content": quick_message.content });
+            entities.push(entity(
+                "button",
+                &format!("quick_message_{}", slug(&quick_message.name)),
+                json!({
+                    "name": quick_message.name,
+                    "icon": "mdi:message-fast",
+                    "command_topic": say_channel_topic,
+                    "payload_press": payload.to_string(),
+                }),
+                true,
+            ));
+        }
+
+        Ok(Arc::new(Self {
+            client,
+            discovery_prefix: config.discovery_prefix.clone(),
+            node_id,
+            entities,
+            publish_config: app_config.mqtt.publish.status,
+        }))
+    }
+
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.discovery_prefix)
+    }
+
+    fn config_topic(&self, entity: &Entity) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.discovery_prefix, entity.component, self.node_id, entity.object_id
+        )
+    }
+
+    /// Remove entities that are gone from the current discovery, all of them if it's disabled
+    pub async fn unpublish_removed(&self, current: Option<&Self>) {
+        let current_topics: Vec<_> = current
+            .map(|current| {
+                current
+                    .entities
+                    .iter()
+                    .map(|entity| current.config_topic(entity))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for entity in &self.entities {
+            let topic = self.config_topic(entity);
+            if current_topics.contains(&topic) {
+                continue;
+            }
+            info!("Removing Home Assistant entity {}", entity.object_id);
+            // an empty retained config deletes the entity
+            if let Err(e) = self
+                .client
+                .publish(&topic, self.publish_config.qos.into(), true, "")
+                .await
+            {
+                error!("Failed sending mqtt message {e}");
+            }
+        }
+    }
+
+    /// Discovery configs are retained so this only needs repeating when Home Assistant restarts
+    pub async fn publish(&self) {
+        info!(
+            "Publishing Home Assistant discovery for {} entities",
+            self.entities.len()
+        );
+        for entity in &self.entities {
+            let topic = self.config_topic(entity);
+            if let Err(e) = self
+                .client
+                .publish(
+                    &topic,
+                    self.publish_config.qos.into(),
+                    true,
+                    entity.config.to_string(),
+                )
+                .await
+            {
+                error!("Failed sending mqtt message {e}");
+            }
+        }
+    }
+}
+
+/// Home Assistant publishes "online" on its status topic when it starts
+pub struct HomeAssistantStatusHandler {
+    discovery: Arc<HomeAssistantDiscovery>,
+}
+
+impl HomeAssistantStatusHandler {
+    pub fn new(discovery: Arc<HomeAssistantDiscovery>) -> Box<Self> {
+        Box::new(Self { discovery })
+    }
+}
+
+#[async_trait]
+impl RouteHandler for HomeAssistantStatusHandler {
+    async fn call(&mut self, _topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+        let status = std::str::from_utf8(content)
+            .map_err(|e| HandlerError::InvalidPayload(e.to_string()))?;
+        if status.trim() == "online" {
+            self.discovery.publish().await;
+        }
+        Ok(())
+    }
+}
+
+fn merge(target: &mut Value, extra: &Value) {
+    if let (Some(target), Some(extra)) = (target.as_object_mut(), extra.as_object()) {
+        for (key, value) in extra {
+            target.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// Home Assistant ids only allow letters, digits, underscores and dashes
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -1,4 +1,8 @@
+mod connection_state;
+mod errors;
+mod home_assistant;
 mod mqtt_server;
 mod routes;
 
+pub use connection_state::MqttConnectionTracker;
 pub use mqtt_server::start_mqtt_service;
@@ -1,20 +1,48 @@
 use super::routes::{
-    DiscordChannelMessageHandler, DoorSensorHandler, MotionSensorHandler, SwitchHandler,
+    DiscordChannelMessageHandler, DoorSensorHandler, HouseModeHandler, MotionSensorHandler,
+    SwitchHandler,
 };
 use crate::{
-    configuration::AppConfig,
-    mqtt::routes::{DiscordChannelFileMessageHandler, DiscordChannelShowTypingHandler},
+    configuration::{
+        AppConfig, ClientKeyType, MqttConfig, OutboundRouteConfig, PublishConfig, Qos,
+    },
+    discord_connection::{DiscordConnectionState, DiscordConnectionTracker},
+    house_mode::HouseModeStore,
+    metered_channel::{metered_channel, ChannelDepth, MeteredSender},
+    mqtt::{
+        connection_state::{MqttConnectionState, MqttConnectionTracker},
+        errors::ErrorPublisher,
+        home_assistant::{HomeAssistantDiscovery, HomeAssistantStatusHandler},
+        routes::{
+            DiscordChannelFileMessageHandler, DiscordChannelShowTypingHandler, OutboundRoute,
+        },
+    },
+    notifications::Notifier,
+    outbound_queue::OutboundQueue,
 };
+use anyhow::Context;
 use log::*;
 use mqtt_router::Router;
-use rumqttc::{AsyncClient, ConnAck, Event, Incoming, MqttOptions, Publish, QoS, SubscribeFilter};
+use rand::Rng;
+use rumqttc::{
+    AsyncClient, ConnAck, Event, EventLoop, Incoming, Key, LastWill, MqttOptions, Outgoing,
+    Publish, QoS, SubscribeFilter, TlsConfiguration, Transport,
+};
 use serde::Serialize;
 use serenity::model::channel::Message;
 use serenity::{http::Http, model::prelude::Attachment};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc::Receiver, oneshot, watch},
+    task::{JoinHandle, JoinSet},
+};
 
 const MQTT_MAX_PACKET_SIZE: usize = 268435455;
+/// MQTT messages waiting for the router
+/// Incoming publishes are dropped when the router falls behind
+const MQTT_MESSAGE_QUEUE_SIZE: usize = 100;
+const MQTT_INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MQTT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 enum MqttUpdate {
     Message(Publish),
@@ -22,166 +50,696 @@ enum MqttUpdate {
 }
 
 pub fn start_mqtt_service(
-    app_config: AppConfig,
+    config_updates: watch::Receiver<AppConfig>,
     discord_http: Arc<Http>,
-    mut discord_message_receiver: UnboundedReceiver<Message>,
-) -> anyhow::Result<()> {
-    let mut mqttoptions = MqttOptions::new(
-        &app_config.mqtt.client_id,
-        &app_config.mqtt.broker_host,
+    discord_message_receiver: Receiver<Message>,
+    house_mode: Arc<HouseModeStore>,
+    outbound_queue: Arc<OutboundQueue>,
+    connection_tracker: Arc<MqttConnectionTracker>,
+    discord_connection: Arc<DiscordConnectionTracker>,
+) -> anyhow::Result<MqttService> {
+    // connection settings only apply at startup, routes follow reloads
+    let app_config = config_updates.borrow().clone();
+    let mqttoptions = mqtt_options(&app_config.mqtt)?;
+    let status_publish = app_config.mqtt.publish.status;
+    info!(
+        "Starting MQTT client for {}:{} as {:?} with tls {}",
+        app_config.mqtt.broker_host,
         app_config.mqtt.broker_port,
+        app_config.mqtt.username,
+        app_config.mqtt.tls.enabled
     );
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-    mqttoptions.set_max_packet_size(MQTT_MAX_PACKET_SIZE, MQTT_MAX_PACKET_SIZE);
-    info!("Starting MQTT server with options {:?}", mqttoptions);
 
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    let (client, eventloop) = AsyncClient::new(mqttoptions, 10);
 
-    let base_topic = app_config.mqtt.base_route;
+    let base_topic = app_config.mqtt.base_route.clone();
 
     info!("MQTT base topic {}", base_topic);
 
-    tokio::spawn({
-        let mqtt_client = client.clone();
-        let topic_v1 = format!("{base_topic}/new_message/v1");
-        let topic_serenity_format_v1 = format!("{base_topic}/new_message/serenity_format/v1");
-        async move {
-            loop {
-                let message = discord_message_receiver.recv().await.unwrap();
-                // send using my own converted message format
-                let mqtt_payload: ReceivedDiscordMessage = message.clone().into();
-                let json =
-                    serde_json::to_string(&mqtt_payload).expect("Failed to serialize message");
-                if let Err(e) = mqtt_client
-                    .publish(&topic_v1, QoS::AtMostOnce, false, json)
-                    .await
-                {
-                    error!("Failed sending mqtt message {e}");
-                }
+    let (message_sender, message_receiver) =
+        metered_channel("MQTT message", "mqtt_messages", MQTT_MESSAGE_QUEUE_SIZE);
 
-                // send using the serenity message format
-                let serenity_format_json =
-                    serde_json::to_string(&message).expect("Failed to serialize message");
-                if let Err(e) = mqtt_client
-                    .publish(
-                        &topic_serenity_format_v1,
-                        QoS::AtMostOnce,
-                        false,
-                        serenity_format_json,
-                    )
-                    .await
-                {
-                    error!("Failed sending mqtt message {e}");
-                }
-            }
+    let mut tasks = JoinSet::new();
+    tasks.spawn(publish_discord_messages(
+        client.clone(),
+        discord_message_receiver,
+        base_topic.clone(),
+        app_config.mqtt.publish.new_message,
+    ));
+    tasks.spawn(publish_house_mode(
+        client.clone(),
+        house_mode.clone(),
+        base_topic.clone(),
+        app_config.mqtt.publish.house_mode,
+    ));
+    tasks.spawn(report_dropped_messages(
+        ErrorPublisher::new(client.clone(), &base_topic, app_config.mqtt.publish.errors),
+        outbound_queue.clone(),
+    ));
+    tasks.spawn(publish_status(
+        client.clone(),
+        connection_tracker.subscribe(),
+        discord_connection.subscribe(),
+        status_topic(&base_topic),
+        app_config.mqtt.publish.status,
+    ));
+    tasks.spawn(publish_discord_status(
+        client.clone(),
+        discord_connection.subscribe(),
+        format!("{base_topic}/discord_status/v1"),
+        app_config.mqtt.publish.discord_status,
+    ));
+    let eventloop = tokio::spawn(run_eventloop(
+        eventloop,
+        message_sender.clone(),
+        connection_tracker,
+    ));
+    let (stop_router, router_stopped) = oneshot::channel();
+    let router = tokio::spawn(run_router(
+        client.clone(),
+        message_receiver,
+        router_stopped,
+        config_updates,
+        discord_http,
+        house_mode,
+        outbound_queue,
+    ));
+
+    Ok(MqttService {
+        client,
+        status_topic: status_topic(&base_topic),
+        status_publish,
+        stop_router: Some(stop_router),
+        message_sender,
+        router,
+        eventloop,
+        tasks,
+    })
+}
+
+/// Handle to the running MQTT tasks
+pub struct MqttService {
+    client: AsyncClient,
+    status_topic: String,
+    status_publish: PublishConfig,
+    stop_router: Option<oneshot::Sender<()>>,
+    message_sender: MeteredSender<MqttUpdate>,
+    router: JoinHandle<anyhow::Result<()>>,
+    eventloop: JoinHandle<anyhow::Result<()>>,
+    tasks: JoinSet<anyhow::Result<()>>,
+}
+
+impl MqttService {
+    /// None of the tasks should ever finish
+    /// so the first one that does takes the whole service down
+    pub async fn wait_for_failure(&mut self) -> anyhow::Error {
+        let result = tokio::select! {
+            result = &mut self.eventloop => Some(result),
+            result = &mut self.router => Some(result),
+            result = self.tasks.join_next() => result,
+        };
+        match result {
+            Some(Ok(Ok(()))) => anyhow::anyhow!("MQTT task stopped unexpectedly"),
+            Some(Ok(Err(e))) => e.context("MQTT task failed"),
+            Some(Err(e)) => anyhow::Error::new(e).context("MQTT task panicked"),
+            None => anyhow::anyhow!("No MQTT tasks running"),
         }
-    });
+    }
 
-    let (message_sender, mut message_receiver) = unbounded_channel();
+    /// Messages from the broker waiting for the router
+    pub fn message_queue(&self) -> Box<dyn ChannelDepth> {
+        Box::new(self.message_sender.clone())
+    }
 
-    tokio::spawn(async move {
-        loop {
-            match eventloop.poll().await {
-                Ok(notification) => match notification {
-                    Event::Incoming(Incoming::Publish(publish)) => {
-                        if let Err(e) = message_sender.send(MqttUpdate::Message(publish)) {
-                            eprintln!("Error sending message {}", e);
-                        }
-                    }
-                    Event::Incoming(Incoming::ConnAck(con_ack)) => {
-                        if let Err(e) = message_sender.send(MqttUpdate::Reconnection(con_ack)) {
-                            eprintln!("Error sending message {}", e);
-                        }
-                    }
-                    _ => (),
-                },
-                Err(e) => {
-                    eprintln!("Error processing eventloop notifications {}", e);
-                }
+    /// Unsubscribe and handle the messages already received
+    /// anything still waiting after the timeout is dropped
+    pub async fn stop_input(&mut self, timeout: Duration) {
+        if let Some(stop_router) = self.stop_router.take() {
+            // the router only exits early if it failed, which wait_for_failure reported
+            let _ = stop_router.send(());
+        }
+        match tokio::time::timeout(timeout, &mut self.router).await {
+            Ok(Ok(Ok(()))) => info!("Handled remaining MQTT messages"),
+            Ok(Ok(Err(e))) => error!("MQTT router failed during shutdown {e}"),
+            Ok(Err(e)) => error!("MQTT router panicked during shutdown {e}"),
+            Err(_) => {
+                warn!("Timed out handling remaining MQTT messages");
+                self.router.abort();
             }
         }
-    });
+    }
 
-    tokio::spawn(async move {
-        let mut router = Router::default();
+    /// Publish our offline status and disconnect once everything queued so far is sent
+    pub async fn shutdown(mut self, timeout: Duration) {
+        let publish = async {
+            self.client
+                .publish(
+                    &self.status_topic,
+                    self.status_publish.qos.into(),
+                    self.status_publish.retain,
+                    BotStatus::offline().to_json()?,
+                )
+                .await?;
+            self.client.disconnect().await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = publish.await {
+            error!("Failed publishing offline status {e}");
+        }
+        // the eventloop stops once the disconnect has been sent
+        match tokio::time::timeout(timeout, &mut self.eventloop).await {
+            Ok(Ok(Ok(()))) => info!("Disconnected from MQTT broker"),
+            Ok(Ok(Err(e))) => error!("MQTT eventloop failed during shutdown {e}"),
+            Ok(Err(e)) => error!("MQTT eventloop panicked during shutdown {e}"),
+            Err(_) => warn!("Timed out disconnecting from MQTT broker"),
+        }
+        self.router.abort();
+        self.tasks.shutdown().await;
+    }
+}
 
-        router
-            .add_handler(
-                "zigbee2mqtt/main_door",
-                DoorSensorHandler::new(discord_http.clone(), app_config.home.spam_channel_id),
-            )
-            .unwrap();
+fn mqtt_options(config: &MqttConfig) -> anyhow::Result<MqttOptions> {
+    let mut mqttoptions =
+        MqttOptions::new(&config.client_id, &config.broker_host, config.broker_port);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    mqttoptions.set_max_packet_size(MQTT_MAX_PACKET_SIZE, MQTT_MAX_PACKET_SIZE);
+    // the broker publishes this for us if we drop off without disconnecting
+    mqttoptions.set_last_will(LastWill::new(
+        status_topic(&config.base_route),
+        BotStatus::offline().to_json()?,
+        config.publish.status.qos.into(),
+        config.publish.status.retain,
+    ));
 
-        router
-            .add_handler(
-                "zigbee2mqtt/switch/#",
-                SwitchHandler::new(discord_http.clone(), app_config.home.spam_channel_id),
-            )
-            .unwrap();
+    if let Some(username) = &config.username {
+        let password = config
+            .password
+            .as_ref()
+            .map(|password| password.expose().to_owned())
+            .unwrap_or_default();
+        mqttoptions.set_credentials(username, password);
+    }
 
-        router
-            .add_handler(
-                "zigbee2mqtt/motion/#",
-                MotionSensorHandler::new(discord_http.clone(), app_config.home.spam_channel_id),
+    if config.tls.enabled {
+        let read_file = |path: &PathBuf| {
+            std::fs::read(path).with_context(|| format!("Failed reading {path:?}"))
+        };
+        let ca = read_file(
+            config
+                .tls
+                .ca_file
+                .as_ref()
+                .context("MQTT TLS requires ca_file")?,
+        )?;
+        let client_auth = match (
+            &config.tls.client_certificate_file,
+            &config.tls.client_key_file,
+        ) {
+            (Some(certificate_file), Some(key_file)) => {
+                let key = read_file(key_file)?;
+                let key = match config.tls.client_key_type {
+                    ClientKeyType::Rsa => Key::RSA(key),
+                    ClientKeyType::Ecc => Key::ECC(key),
+                };
+                Some((read_file(certificate_file)?, key))
+            }
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "MQTT TLS client authentication needs both client_certificate_file and client_key_file"
+            ),
+        };
+        mqttoptions.set_transport(Transport::tls_with_config(TlsConfiguration::Simple {
+            ca,
+            alpn: None,
+            client_auth,
+        }));
+    }
+    Ok(mqttoptions)
+}
+
+async fn publish_discord_messages(
+    mqtt_client: AsyncClient,
+    mut discord_message_receiver: Receiver<Message>,
+    base_topic: String,
+    publish_config: PublishConfig,
+) -> anyhow::Result<()> {
+    let topic_v1 = format!("{base_topic}/new_message/v1");
+    let topic_serenity_format_v1 = format!("{base_topic}/new_message/serenity_format/v1");
+    while let Some(message) = discord_message_receiver.recv().await {
+        // send using my own converted message format
+        let mqtt_payload: ReceivedDiscordMessage = message.clone().into();
+        let json = serde_json::to_string(&mqtt_payload).context("Failed to serialize message")?;
+        if let Err(e) = mqtt_client
+            .publish(
+                &topic_v1,
+                publish_config.qos.into(),
+                publish_config.retain,
+                json,
             )
-            .unwrap();
+            .await
+        {
+            error!("Failed sending mqtt message {e}");
+        }
 
-        router
-            .add_handler(
-                &format!("{base_topic}/say_channel"),
-                DiscordChannelMessageHandler::new(discord_http.clone()),
+        // send using the serenity message format
+        let serenity_format_json =
+            serde_json::to_string(&message).context("Failed to serialize message")?;
+        if let Err(e) = mqtt_client
+            .publish(
+                &topic_serenity_format_v1,
+                publish_config.qos.into(),
+                publish_config.retain,
+                serenity_format_json,
             )
-            .unwrap();
+            .await
+        {
+            error!("Failed sending mqtt message {e}");
+        }
+    }
+    anyhow::bail!("Discord message channel closed")
+}
 
-        router
-            .add_handler(
-                &format!("{base_topic}/send_file_channel"),
-                DiscordChannelFileMessageHandler::new(discord_http.clone()),
+async fn publish_house_mode(
+    mqtt_client: AsyncClient,
+    house_mode: Arc<HouseModeStore>,
+    base_topic: String,
+    publish_config: PublishConfig,
+) -> anyhow::Result<()> {
+    let mut house_mode_receiver = house_mode.subscribe();
+    let topic = format!("{base_topic}/house_mode/v1");
+    loop {
+        let mode = *house_mode_receiver.borrow_and_update();
+        let json = serde_json::json!({ "mode": mode }).to_string();
+        if let Err(e) = mqtt_client
+            .publish(
+                &topic,
+                publish_config.qos.into(),
+                publish_config.retain,
+                json,
             )
-            .unwrap();
+            .await
+        {
+            error!("Failed sending mqtt message {e}");
+        }
+        house_mode_receiver
+            .changed()
+            .await
+            .context("House mode channel closed")?;
+    }
+}
+
+fn status_topic(base_topic: &str) -> String {
+    format!("{base_topic}/status")
+}
+
+/// Availability of the bot, published retained so consumers see it on subscribe
+#[derive(Debug, Serialize)]
+struct BotStatus {
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    discord_connected: Option<bool>,
+}
+
+impl BotStatus {
+    fn online(discord_connected: bool) -> Self {
+        Self {
+            state: "online",
+            version: Some(env!("CARGO_PKG_VERSION")),
+            discord_connected: Some(discord_connected),
+        }
+    }
+
+    fn offline() -> Self {
+        Self {
+            state: "offline",
+            version: None,
+            discord_connected: None,
+        }
+    }
+
+    fn to_json(&self) -> anyhow::Result<String> {
+        serde_json::to_string(self).context("Failed to serialize status")
+    }
+}
+
+/// Publish online after every connection to the broker and whenever discord connects or disconnects
+async fn publish_status(
+    mqtt_client: AsyncClient,
+    mut mqtt_connection: watch::Receiver<MqttConnectionState>,
+    mut discord_connection: watch::Receiver<DiscordConnectionState>,
+    topic: String,
+    publish_config: PublishConfig,
+) -> anyhow::Result<()> {
+    let mut last_published = None;
+    loop {
+        let mqtt = mqtt_connection.borrow_and_update().clone();
+        let discord_connected = discord_connection.borrow_and_update().connected;
+        // the broker may have published our last will while we were away
+        let current = (mqtt.reconnects, discord_connected);
+        if mqtt.connected && last_published != Some(current) {
+            last_published = Some(current);
+            let status = BotStatus::online(discord_connected);
+            if let Err(e) = mqtt_client
+                .publish(
+                    &topic,
+                    publish_config.qos.into(),
+                    publish_config.retain,
+                    status.to_json()?,
+                )
+                .await
+            {
+                error!("Failed sending mqtt message {e}");
+            }
+        }
+        tokio::select! {
+            changed = mqtt_connection.changed() => changed.context("MQTT connection channel closed")?,
+            changed = discord_connection.changed() => changed.context("Discord connection channel closed")?,
+        }
+    }
+}
 
-        router
-            .add_handler(
-                &format!("{base_topic}/show_typing_channel"),
-                DiscordChannelShowTypingHandler::new(discord_http.clone()),
+async fn publish_discord_status(
+    mqtt_client: AsyncClient,
+    mut discord_connection: watch::Receiver<DiscordConnectionState>,
+    topic: String,
+    publish_config: PublishConfig,
+) -> anyhow::Result<()> {
+    loop {
+        let json = serde_json::to_string(&*discord_connection.borrow_and_update())
+            .context("Failed to serialize discord status")?;
+        if let Err(e) = mqtt_client
+            .publish(
+                &topic,
+                publish_config.qos.into(),
+                publish_config.retain,
+                json,
             )
-            .unwrap();
-
-        let topics = router
-            .topics_for_subscription()
-            .map(|topic| SubscribeFilter {
-                path: topic.to_owned(),
-                qos: QoS::AtMostOnce,
-            });
-        client.subscribe_many(topics).await.unwrap();
-
-        loop {
-            let update = message_receiver.recv().await.unwrap();
-            match update {
-                MqttUpdate::Message(message) => {
-                    match router
-                        .handle_message_ignore_errors(&message.topic, &message.payload)
-                        .await
-                    {
-                        Ok(false) => error!("No handler for topic: \"{}\"", &message.topic),
-                        Ok(true) => (),
-                        Err(e) => error!("Failed running handler with {:?}", e),
+            .await
+        {
+            error!("Failed sending mqtt message {e}");
+        }
+        discord_connection
+            .changed()
+            .await
+            .context("Discord connection channel closed")?;
+    }
+}
+
+async fn report_dropped_messages(
+    errors: ErrorPublisher,
+    outbound_queue: Arc<OutboundQueue>,
+) -> anyhow::Result<()> {
+    let mut dropped_receiver = outbound_queue.subscribe_dropped();
+    loop {
+        match dropped_receiver.recv().await {
+            Ok(dropped) => errors.publish_dropped(&dropped).await,
+            Err(RecvError::Lagged(count)) => warn!("Missed reporting {count} dropped messages"),
+            Err(RecvError::Closed) => anyhow::bail!("Dropped message channel closed"),
+        }
+    }
+}
+
+async fn run_eventloop(
+    mut eventloop: EventLoop,
+    message_sender: MeteredSender<MqttUpdate>,
+    connection_tracker: Arc<MqttConnectionTracker>,
+) -> anyhow::Result<()> {
+    let mut consecutive_failures = 0;
+    loop {
+        match eventloop.poll().await {
+            Ok(notification) => match notification {
+                Event::Incoming(Incoming::Publish(publish)) => {
+                    // don't stall the eventloop or we miss keep alive
+                    message_sender.send_or_drop(MqttUpdate::Message(publish));
+                }
+                Event::Outgoing(Outgoing::Disconnect) => {
+                    info!("Sent disconnect to MQTT broker");
+                    return Ok(());
+                }
+                Event::Incoming(Incoming::ConnAck(con_ack)) => {
+                    consecutive_failures = 0;
+                    connection_tracker.connected();
+                    // we must resubscribe so wait for space
+                    message_sender
+                        .send(MqttUpdate::Reconnection(con_ack))
+                        .await?;
+                }
+                _ => (),
+            },
+            Err(e) => {
+                consecutive_failures += 1;
+                connection_tracker.disconnected(e.to_string());
+                let delay = reconnect_delay(consecutive_failures);
+                if consecutive_failures == 1 {
+                    warn!("MQTT connection error {e}, retrying in {delay:?}");
+                } else {
+                    debug!(
+                        "MQTT connection error {e}, attempt {consecutive_failures}, retrying in {delay:?}"
+                    );
+                }
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Exponential backoff with up to 50% jitter
+fn reconnect_delay(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    let delay = MQTT_INITIAL_RECONNECT_DELAY
+        .saturating_mul(2_u32.pow(exponent))
+        .min(MQTT_MAX_RECONNECT_DELAY);
+    let jitter_ms = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+    delay + Duration::from_millis(jitter_ms)
+}
+
+/// Shared by every router we build so state survives configuration reloads
+struct RouterContext {
+    client: AsyncClient,
+    errors: ErrorPublisher,
+    discord_http: Arc<Http>,
+    house_mode: Arc<HouseModeStore>,
+    outbound_queue: Arc<OutboundQueue>,
+    notifier: Arc<Notifier>,
+}
+
+async fn run_router(
+    client: AsyncClient,
+    mut message_receiver: Receiver<MqttUpdate>,
+    mut stopped: oneshot::Receiver<()>,
+    mut config_updates: watch::Receiver<AppConfig>,
+    discord_http: Arc<Http>,
+    house_mode: Arc<HouseModeStore>,
+    outbound_queue: Arc<OutboundQueue>,
+) -> anyhow::Result<()> {
+    let mut app_config = config_updates.borrow_and_update().clone();
+    let notifier = Notifier::new(
+        outbound_queue.clone(),
+        app_config.home.clone(),
+        house_mode.clone(),
+    );
+    notifier.start_digest_task();
+    let context = RouterContext {
+        client: client.clone(),
+        errors: ErrorPublisher::new(
+            client.clone(),
+            &app_config.mqtt.base_route,
+            app_config.mqtt.publish.errors,
+        ),
+        discord_http,
+        house_mode,
+        outbound_queue,
+        notifier,
+    };
+
+    let (mut router, mut discovery) = build_router(&context, &app_config)?;
+    let mut subscriptions = subscription_filters(&router, &app_config.mqtt);
+    subscribe(&client, subscriptions.clone()).await?;
+
+    loop {
+        tokio::select! {
+            update = message_receiver.recv() => match update.context("MQTT message channel closed")? {
+                MqttUpdate::Message(message) => route_message(&mut router, &message).await,
+                MqttUpdate::Reconnection(con_ack) => {
+                    info!(
+                        "Connected to broker, session present {}",
+                        con_ack.session_present
+                    );
+                    subscribe(&client, subscriptions.clone()).await?;
+                    if let Some(discovery) = &discovery {
+                        discovery.publish().await;
+                    }
+                }
+            },
+            _ = &mut stopped => {
+                for filter in &subscriptions {
+                    if let Err(e) = client.unsubscribe(&filter.path).await {
+                        error!("Failed to unsubscribe from {} {e}", filter.path);
+                    }
+                }
+                // messages that arrived before the unsubscribe still get handled
+                while let Ok(update) = message_receiver.try_recv() {
+                    if let MqttUpdate::Message(message) = update {
+                        route_message(&mut router, &message).await;
                     }
                 }
-                MqttUpdate::Reconnection(_) => {
-                    info!("Reconnecting to broker");
-                    let topics = router
-                        .topics_for_subscription()
-                        .map(|topic| SubscribeFilter {
-                            path: topic.to_owned(),
-                            qos: QoS::AtMostOnce,
-                        });
-                    client.subscribe_many(topics).await.unwrap();
+                return Ok(());
+            }
+            changed = config_updates.changed() => {
+                changed.context("Configuration channel closed")?;
+                app_config = config_updates.borrow_and_update().clone();
+                context.notifier.update_settings(app_config.home.clone());
+                match build_router(&context, &app_config) {
+                    Ok((new_router, new_discovery)) => {
+                        let new_subscriptions = subscription_filters(&new_router, &app_config.mqtt);
+                        unsubscribe_removed(&client, &subscriptions, &new_subscriptions).await?;
+                        subscribe(&client, new_subscriptions.clone()).await?;
+                        if let Some(previous) = &discovery {
+                            previous.unpublish_removed(new_discovery.as_deref()).await;
+                        }
+                        if let Some(discovery) = &new_discovery {
+                            discovery.publish().await;
+                        }
+                        router = new_router;
+                        discovery = new_discovery;
+                        subscriptions = new_subscriptions;
+                        info!("Rebuilt MQTT routes from reloaded configuration");
+                    }
+                    Err(e) => error!("Keeping current MQTT routes, failed rebuilding them {e:?}"),
                 }
             }
         }
-    });
+    }
+}
+
+/// Register handlers for everything we listen to
+fn build_router(
+    context: &RouterContext,
+    app_config: &AppConfig,
+) -> anyhow::Result<(Router, Option<Arc<HomeAssistantDiscovery>>)> {
+    let base_topic = &app_config.mqtt.base_route;
+    let errors = &context.errors;
+    let notifier = &context.notifier;
+    let mut router = Router::default();
+    let channel_aliases = Arc::new(app_config.home.channel_aliases.clone());
+    let outbound_route =
+        |config: &OutboundRouteConfig| OutboundRoute::new(config.clone(), channel_aliases.clone());
+
+    router.add_handler(
+        "zigbee2mqtt/main_door",
+        errors.wrap(DoorSensorHandler::new(notifier.clone())),
+    )?;
+
+    router.add_handler(
+        "zigbee2mqtt/switch/#",
+        errors.wrap(SwitchHandler::new(notifier.clone())),
+    )?;
+
+    router.add_handler(
+        "zigbee2mqtt/motion/#",
+        errors.wrap(MotionSensorHandler::new(notifier.clone())),
+    )?;
+
+    router.add_handler(
+        &format!("{base_topic}/house_mode/set"),
+        errors.wrap(HouseModeHandler::new(context.house_mode.clone())),
+    )?;
+
+    router.add_handler(
+        &format!("{base_topic}/say_channel"),
+        errors.wrap(DiscordChannelMessageHandler::new(
+            context.outbound_queue.clone(),
+            outbound_route(&app_config.mqtt.outbound_routes.say_channel),
+        )),
+    )?;
+
+    router.add_handler(
+        &format!("{base_topic}/send_file_channel"),
+        errors.wrap(DiscordChannelFileMessageHandler::new(
+            context.discord_http.clone(),
+            context.outbound_queue.clone(),
+            outbound_route(&app_config.mqtt.outbound_routes.send_file_channel),
+        )),
+    )?;
+
+    router.add_handler(
+        &format!("{base_topic}/show_typing_channel"),
+        errors.wrap(DiscordChannelShowTypingHandler::new(
+            context.discord_http.clone(),
+            context.outbound_queue.clone(),
+            outbound_route(&app_config.mqtt.outbound_routes.show_typing_channel),
+        )),
+    )?;
+
+    let discovery = if app_config.mqtt.home_assistant.enabled {
+        let discovery = HomeAssistantDiscovery::new(context.client.clone(), app_config)?;
+        router.add_handler(
+            &discovery.status_topic(),
+            errors.wrap(HomeAssistantStatusHandler::new(discovery.clone())),
+        )?;
+        Some(discovery)
+    } else {
+        None
+    };
+
+    Ok((router, discovery))
+}
+
+fn subscription_filters(router: &Router, config: &MqttConfig) -> Vec<SubscribeFilter> {
+    router
+        .topics_for_subscription()
+        .map(|topic| SubscribeFilter {
+            qos: config.subscription_qos(topic).into(),
+            path: topic.to_owned(),
+        })
+        .collect()
+}
+
+impl From<Qos> for QoS {
+    fn from(qos: Qos) -> Self {
+        match qos {
+            Qos::AtMostOnce => QoS::AtMostOnce,
+            Qos::AtLeastOnce => QoS::AtLeastOnce,
+            Qos::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+async fn route_message(router: &mut Router, message: &Publish) {
+    match router
+        .handle_message_ignore_errors(&message.topic, &message.payload)
+        .await
+    {
+        Ok(false) => error!("No handler for topic: \"{}\"", &message.topic),
+        Ok(true) => (),
+        Err(e) => error!("Failed running handler with {:?}", e),
+    }
+}
 
+async fn subscribe(client: &AsyncClient, filters: Vec<SubscribeFilter>) -> anyhow::Result<()> {
+    client
+        .subscribe_many(filters)
+        .await
+        .context("Failed to subscribe")?;
+    Ok(())
+}
+
+async fn unsubscribe_removed(
+    client: &AsyncClient,
+    previous: &[SubscribeFilter],
+    current: &[SubscribeFilter],
+) -> anyhow::Result<()> {
+    for filter in previous {
+        if !current.iter().any(|current| current.path == filter.path) {
+            info!("Unsubscribing from {}", filter.path);
+            client
+                .unsubscribe(&filter.path)
+                .await
+                .context("Failed to unsubscribe")?;
+        }
+    }
     Ok(())
 }
 
@@ -241,3 +799,47 @@ impl From<Attachment> for MessageAttachment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_delay_between(consecutive_failures: u32, min: Duration, max: Duration) {
+        for _ in 0..100 {
+            let delay = reconnect_delay(consecutive_failures);
+            assert!(
+                delay >= min && delay <= max,
+                "{delay:?} after {consecutive_failures} failures not in {min:?}..={max:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn reconnect_delay_starts_at_the_initial_delay() {
+        assert_delay_between(
+            1,
+            MQTT_INITIAL_RECONNECT_DELAY,
+            MQTT_INITIAL_RECONNECT_DELAY * 3 / 2,
+        );
+    }
+
+    #[test]
+    fn reconnect_delay_doubles_with_each_failure() {
+        assert_delay_between(
+            4,
+            MQTT_INITIAL_RECONNECT_DELAY * 8,
+            MQTT_INITIAL_RECONNECT_DELAY * 12,
+        );
+    }
+
+    #[test]
+    fn reconnect_delay_is_capped_with_jitter() {
+        for consecutive_failures in [10, 17, 100, u32::MAX] {
+            assert_delay_between(
+                consecutive_failures,
+                MQTT_MAX_RECONNECT_DELAY,
+                MQTT_MAX_RECONNECT_DELAY * 3 / 2,
+            );
+        }
+    }
+}
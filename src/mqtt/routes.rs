@@ -1,3 +1,11 @@
+use super::errors::HandlerError;
+use crate::{
+    configuration::{ChannelRef, MentionPolicy, MentionTargets, OutboundRouteConfig},
+    house_mode::{HouseMode, HouseModeStore},
+    metrics::time_discord_request,
+    notifications::{allow_only, Notifier, SensorEvent},
+    outbound_queue::{MessageOrigin, OutboundMessage, OutboundQueue},
+};
 use anyhow::Context;
 use async_trait::async_trait;
 use base64::{engine::general_purpose, Engine};
@@ -5,41 +13,44 @@ use log::*;
 use mqtt_router::{RouteHandler, RouterError};
 use serde::Deserialize;
 use serenity::{http::Http, model::id::ChannelId};
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use tempdir::TempDir;
 
+const ZIGBEE_BASE_TOPIC: &str = "zigbee2mqtt/";
+
+/// Device name used in configuration, e.g. "main_door" or "switch/desk"
+fn device_name(topic: &str) -> &str {
+    topic.strip_prefix(ZIGBEE_BASE_TOPIC).unwrap_or(topic)
+}
+
 pub struct DoorSensorHandler {
-    discord: Arc<Http>,
-    discord_channel: ChannelId,
+    notifier: Arc<Notifier>,
 }
 
 impl DoorSensorHandler {
-    pub fn new(discord: Arc<Http>, discord_channel_id: u64) -> Box<Self> {
-        Box::new(Self {
-            discord,
-            discord_channel: ChannelId(discord_channel_id),
-        })
+    pub fn new(notifier: Arc<Notifier>) -> Box<Self> {
+        Box::new(Self { notifier })
     }
 }
 
 #[async_trait]
 impl RouteHandler for DoorSensorHandler {
-    async fn call(&mut self, _topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+    async fn call(&mut self, topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
         info!("Handling door sensor data");
         let door_sensor: DoorSensor =
-            serde_json::from_slice(content).map_err(|e| RouterError::HandlerError(e.into()))?;
+            serde_json::from_slice(content).map_err(HandlerError::Parse)?;
 
-        if door_sensor.contact {
-            self.discord_channel
-                .say(&self.discord, "Front door was closed")
-                .await
-                .map_err(|e| RouterError::HandlerError(e.into()))?;
+        let device = device_name(topic);
+        let event = if door_sensor.contact {
+            SensorEvent::new(device, "Front door was closed")
         } else {
-            self.discord_channel
-                .say(&self.discord, "Front door was opened")
-                .await
-                .map_err(|e| RouterError::HandlerError(e.into()))?;
-        }
+            SensorEvent::new(device, "Front door was opened").critical()
+        };
+
+        self.notifier
+            .notify(event)
+            .await
+            .map_err(HandlerError::from)?;
 
         Ok(())
     }
@@ -61,38 +72,32 @@ pub struct DoorSensor {
 }
 
 pub struct MotionSensorHandler {
-    discord: Arc<Http>,
-    discord_channel: ChannelId,
+    notifier: Arc<Notifier>,
 }
 
 impl MotionSensorHandler {
-    #[allow(dead_code)]
-    pub fn new(discord: Arc<Http>, discord_channel_id: u64) -> Box<Self> {
-        Box::new(Self {
-            discord,
-            discord_channel: ChannelId(discord_channel_id),
-        })
+    pub fn new(notifier: Arc<Notifier>) -> Box<Self> {
+        Box::new(Self { notifier })
     }
 }
 
 #[async_trait]
 impl RouteHandler for MotionSensorHandler {
-    async fn call(&mut self, _topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+    async fn call(&mut self, topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
         info!("Handling motion sensor data");
         let motion_sensor: MotionSensorData =
-            serde_json::from_slice(content).map_err(|e| RouterError::HandlerError(e.into()))?;
+            serde_json::from_slice(content).map_err(HandlerError::Parse)?;
 
-        if motion_sensor.occupancy {
-            self.discord_channel
-                .say(&self.discord, "Motion sensor detected motion")
-                .await
-                .map_err(|e| RouterError::HandlerError(e.into()))?;
+        let message = if motion_sensor.occupancy {
+            "Motion sensor detected motion"
         } else {
-            self.discord_channel
-                .say(&self.discord, "Motion sensors not detecting any motion")
-                .await
-                .map_err(|e| RouterError::HandlerError(e.into()))?;
-        }
+            "Motion sensors not detecting any motion"
+        };
+
+        self.notifier
+            .notify(SensorEvent::new(device_name(topic), message))
+            .await
+            .map_err(HandlerError::from)?;
 
         Ok(())
     }
@@ -114,16 +119,12 @@ struct MotionSensorData {
 }
 
 pub struct SwitchHandler {
-    discord: Arc<Http>,
-    discord_channel: ChannelId,
+    notifier: Arc<Notifier>,
 }
 
 impl SwitchHandler {
-    pub fn new(discord: Arc<Http>, discord_channel_id: u64) -> Box<Self> {
-        Box::new(Self {
-            discord,
-            discord_channel: ChannelId(discord_channel_id),
-        })
+    pub fn new(notifier: Arc<Notifier>) -> Box<Self> {
+        Box::new(Self { notifier })
     }
 }
 
@@ -131,9 +132,9 @@ impl SwitchHandler {
 impl RouteHandler for SwitchHandler {
     async fn call(&mut self, topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
         info!("Handling switch data");
-        let switch_name = topic.split('/').last().unwrap_or("unknown");
+        let switch_name = topic.split('/').next_back().unwrap_or("unknown");
         let switch_data: SwitchPayload =
-            serde_json::from_slice(content).map_err(|err| RouterError::HandlerError(err.into()))?;
+            serde_json::from_slice(content).map_err(HandlerError::Parse)?;
 
         let message = match switch_data.action {
             Action::Single => format!("switch {switch_name} was clicked once"),
@@ -141,10 +142,10 @@ impl RouteHandler for SwitchHandler {
             Action::Double => format!("switch {switch_name} was double clicked"),
         };
 
-        self.discord_channel
-            .say(&self.discord, &message)
+        self.notifier
+            .notify(SensorEvent::new(device_name(topic), message))
             .await
-            .map_err(|e| RouterError::HandlerError(e.into()))?;
+            .map_err(HandlerError::from)?;
         Ok(())
     }
 }
@@ -168,122 +169,282 @@ pub struct SwitchPayload {
     pub voltage: f32,
 }
 
+pub struct HouseModeHandler {
+    house_mode: Arc<HouseModeStore>,
+}
+
+impl HouseModeHandler {
+    pub fn new(house_mode: Arc<HouseModeStore>) -> Box<Self> {
+        Box::new(Self { house_mode })
+    }
+}
+
+#[async_trait]
+impl RouteHandler for HouseModeHandler {
+    async fn call(&mut self, _topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+        info!("Handling house mode change");
+        let request: HouseModeRequest =
+            serde_json::from_slice(content).map_err(HandlerError::Parse)?;
+
+        self.house_mode
+            .set(request.mode)
+            .map_err(HandlerError::from)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HouseModeRequest {
+    mode: HouseMode,
+}
+
 // discord stuff
 
+/// Shared settings for routes that post MQTT payloads to discord
+#[derive(Clone)]
+pub struct OutboundRoute {
+    config: OutboundRouteConfig,
+    channel_aliases: Arc<HashMap<String, u64>>,
+}
+
+impl OutboundRoute {
+    pub fn new(config: OutboundRouteConfig, channel_aliases: Arc<HashMap<String, u64>>) -> Self {
+        Self {
+            config,
+            channel_aliases,
+        }
+    }
+
+    /// Resolve the payload's channel and check it against the allow-list
+    fn resolve_channel(
+        &self,
+        topic: &str,
+        target: &ChannelTarget,
+    ) -> Result<ChannelId, HandlerError> {
+        let channel_ref = match (&target.channel, target.channel_id) {
+            (Some(channel), _) => channel.clone(),
+            (None, Some(channel_id)) => ChannelRef::Id(channel_id),
+            (None, None) => {
+                return Err(HandlerError::InvalidPayload(
+                    "Payload has no channel or channel_id".to_owned(),
+                ))
+            }
+        };
+        let channel_id = channel_ref.resolve(&self.channel_aliases).ok_or_else(|| {
+            HandlerError::InvalidPayload(format!("Unknown channel alias {channel_ref:?}"))
+        })?;
+        if !self
+            .config
+            .is_channel_allowed(channel_id, &self.channel_aliases)
+        {
+            warn!("Rejected channel {channel_id} for {topic}");
+            return Err(HandlerError::Permission(format!(
+                "Channel {channel_id} is not allowed for {topic}"
+            )));
+        }
+        Ok(ChannelId(channel_id))
+    }
+
+    /// Mentions a payload is allowed to ping under the route's policy
+    fn allowed_mentions(&self, mentions: &PayloadMentions) -> MentionTargets {
+        match self.config.mentions {
+            MentionPolicy::None => MentionTargets::default(),
+            MentionPolicy::Payload => MentionTargets {
+                users: mentions.mention_users.clone(),
+                roles: mentions.mention_roles.clone(),
+                everyone: false,
+            },
+        }
+    }
+}
+
+/// Target channel of a payload, either `channel_id` or `channel` with an id or alias
+#[derive(Debug, Deserialize)]
+pub struct ChannelTarget {
+    channel_id: Option<u64>,
+    channel: Option<ChannelRef>,
+}
+
+/// Users and roles a payload asks to ping
+#[derive(Debug, Deserialize, Default)]
+pub struct PayloadMentions {
+    #[serde(default)]
+    mention_users: Vec<u64>,
+    #[serde(default)]
+    mention_roles: Vec<u64>,
+}
+
 pub struct DiscordChannelMessageHandler {
-    discord_http: Arc<Http>,
+    outbound_queue: Arc<OutboundQueue>,
+    route: OutboundRoute,
 }
 
 impl DiscordChannelMessageHandler {
-    pub fn new(discord_http: Arc<Http>) -> Box<Self> {
-        Box::new(Self { discord_http })
+    pub fn new(outbound_queue: Arc<OutboundQueue>, route: OutboundRoute) -> Box<Self> {
+        Box::new(Self {
+            outbound_queue,
+            route,
+        })
     }
 }
 
 #[async_trait]
 impl RouteHandler for DiscordChannelMessageHandler {
-    async fn call(&mut self, _topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+    async fn call(&mut self, topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
         info!("Handling discord send request");
         let message_data: DiscordMessageToChannel =
-            serde_json::from_slice(content).map_err(|err| RouterError::HandlerError(err.into()))?;
-
-        let channel = ChannelId(message_data.channel_id);
-        channel
-            .say(&self.discord_http, &message_data.content)
+            serde_json::from_slice(content).map_err(HandlerError::Parse)?;
+
+        let channel = self.route.resolve_channel(topic, &message_data.target)?;
+
+        let mut message = OutboundMessage::new(channel, message_data.content);
+        message.mentions = self.route.allowed_mentions(&message_data.mentions);
+        message.origin = Some(MessageOrigin {
+            topic: topic.to_owned(),
+            handler: "DiscordChannelMessageHandler".to_owned(),
+            request_id: message_data.request_id,
+        });
+        self.outbound_queue
+            .enqueue(message)
             .await
-            .map_err(|e| RouterError::HandlerError(e.into()))?;
+            .map_err(HandlerError::from)?;
         Ok(())
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DiscordMessageToChannel {
-    channel_id: u64,
+    #[serde(flatten)]
+    target: ChannelTarget,
     content: String,
+    /// Passed back on the errors topic if sending fails
+    #[serde(default)]
+    request_id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    mentions: PayloadMentions,
 }
 
 pub struct DiscordChannelShowTypingHandler {
     discord_http: Arc<Http>,
+    outbound_queue: Arc<OutboundQueue>,
+    route: OutboundRoute,
 }
 
 impl DiscordChannelShowTypingHandler {
-    pub fn new(discord_http: Arc<Http>) -> Box<Self> {
-        Box::new(Self { discord_http })
+    pub fn new(
+        discord_http: Arc<Http>,
+        outbound_queue: Arc<OutboundQueue>,
+        route: OutboundRoute,
+    ) -> Box<Self> {
+        Box::new(Self {
+            discord_http,
+            outbound_queue,
+            route,
+        })
     }
 }
 
 #[async_trait]
 impl RouteHandler for DiscordChannelShowTypingHandler {
-    async fn call(&mut self, _topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+    async fn call(&mut self, topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
         info!("Handling discord send request");
         let message_data: DiscordShowTypingToChannel =
-            serde_json::from_slice(content).map_err(|err| RouterError::HandlerError(err.into()))?;
+            serde_json::from_slice(content).map_err(HandlerError::Parse)?;
+
+        let channel = self.route.resolve_channel(topic, &message_data.target)?;
+
+        // shares the outbound queue rate limit, a dropped request is reported on the errors topic
+        let mut request = OutboundMessage::new(channel, "");
+        request.origin = Some(MessageOrigin {
+            topic: topic.to_owned(),
+            handler: "DiscordChannelShowTypingHandler".to_owned(),
+            request_id: None,
+        });
+        if !self.outbound_queue.acquire_direct(request) {
+            return Ok(());
+        }
 
-        let channel = ChannelId(message_data.channel_id);
-        channel
-            .broadcast_typing(&self.discord_http)
+        time_discord_request("typing", channel.broadcast_typing(&self.discord_http))
             .await
-            .map_err(|e| RouterError::HandlerError(e.into()))?;
+            .map_err(HandlerError::from)?;
         Ok(())
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DiscordShowTypingToChannel {
-    channel_id: u64,
+    #[serde(flatten)]
+    target: ChannelTarget,
 }
 
 pub struct DiscordChannelFileMessageHandler {
     discord_http: Arc<Http>,
+    outbound_queue: Arc<OutboundQueue>,
+    route: OutboundRoute,
 }
 
 impl DiscordChannelFileMessageHandler {
-    pub fn new(discord_http: Arc<Http>) -> Box<Self> {
-        Box::new(Self { discord_http })
+    pub fn new(
+        discord_http: Arc<Http>,
+        outbound_queue: Arc<OutboundQueue>,
+        route: OutboundRoute,
+    ) -> Box<Self> {
+        Box::new(Self {
+            discord_http,
+            outbound_queue,
+            route,
+        })
     }
 }
 
 #[async_trait]
 impl RouteHandler for DiscordChannelFileMessageHandler {
-    async fn call(&mut self, _topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
+    async fn call(&mut self, topic: &str, content: &[u8]) -> std::result::Result<(), RouterError> {
         info!("Handling discord file message send request");
         let message_data: DiscordFileMessageToChannel =
-            serde_json::from_slice(content).map_err(|err| RouterError::HandlerError(err.into()))?;
+            serde_json::from_slice(content).map_err(HandlerError::Parse)?;
+
+        let channel = self.route.resolve_channel(topic, &message_data.target)?;
+
+        // shares the outbound queue rate limit, a dropped request is reported on the errors topic
+        let mut request = OutboundMessage::new(channel, message_data.content.clone());
+        request.origin = Some(MessageOrigin {
+            topic: topic.to_owned(),
+            handler: "DiscordChannelFileMessageHandler".to_owned(),
+            request_id: None,
+        });
+        if !self.outbound_queue.acquire_direct(request) {
+            return Ok(());
+        }
 
-        let temp_dir = TempDir::new("discord_message_temp_dir")
-            .map_err(|err| RouterError::HandlerError(err.into()))?;
+        let temp_dir = TempDir::new("discord_message_temp_dir").map_err(HandlerError::Io)?;
 
         let mut file_paths = Vec::new();
 
         for file in &message_data.files {
             let file_path = temp_dir.path().join(&file.file_name);
 
-            std::fs::write(
-                &file_path,
-                &file
-                    .get_binary_data()
-                    .map_err(|err| RouterError::HandlerError(err.into()))?,
-            )
-            .map_err(|err| RouterError::HandlerError(err.into()))?;
+            std::fs::write(&file_path, file.get_binary_data()?).map_err(HandlerError::Io)?;
 
             let file_path = file_path
                 .as_os_str()
                 .to_str()
                 .context("failed to extract path")
-                .map_err(|err| RouterError::HandlerError(err.into()))?
+                .map_err(HandlerError::Other)?
                 .to_owned();
             file_paths.push(file_path);
         }
         // borrow string for serenity
         let file_paths = file_paths.iter().map(|s| s.as_str()).collect::<Vec<_>>();
 
-        let channel = ChannelId(message_data.channel_id);
-        channel
-            .send_files(&self.discord_http, file_paths, |m| {
-                m.content(&message_data.content)
-            })
+        let mentions = self.route.allowed_mentions(&message_data.mentions);
+        let request = channel.send_files(&self.discord_http, file_paths, |m| {
+            m.content(&message_data.content)
+                .allowed_mentions(|allowed| allow_only(allowed, &mentions))
+        });
+        time_discord_request("send_files", request)
             .await
-            .map_err(|e| RouterError::HandlerError(e.into()))?;
+            .map_err(HandlerError::from)?;
         Ok(())
     }
 }
@@ -295,16 +456,22 @@ pub struct FileAttachment {
 }
 
 impl FileAttachment {
-    fn get_binary_data(&self) -> anyhow::Result<Vec<u8>> {
-        general_purpose::STANDARD
-            .decode(&self.data)
-            .context("Failed to parse base64")
+    fn get_binary_data(&self) -> Result<Vec<u8>, HandlerError> {
+        general_purpose::STANDARD.decode(&self.data).map_err(|e| {
+            HandlerError::InvalidPayload(format!(
+                "Failed to parse base64 of {} {e}",
+                self.file_name
+            ))
+        })
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DiscordFileMessageToChannel {
-    channel_id: u64,
+    #[serde(flatten)]
+    target: ChannelTarget,
     content: String,
     files: Vec<FileAttachment>,
+    #[serde(flatten)]
+    mentions: PayloadMentions,
 }
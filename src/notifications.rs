@@ -0,0 +1,315 @@
+use crate::{
+    configuration::{HomeSettings, MentionTargets, QuietHoursAction},
+    house_mode::{HouseMode, HouseModeStore},
+    metrics::metrics,
+    outbound_queue::{OutboundMessage, OutboundQueue, MAX_MESSAGE_LENGTH},
+};
+use chrono::Utc;
+use log::*;
+use serenity::{
+    builder::{CreateAllowedMentions, ParseValue},
+    model::id::{ChannelId, RoleId, UserId},
+};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+const DIGEST_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Metrics label for devices missing from `home.devices`
+const OTHER_DEVICES_LABEL: &str = "other";
+
+/// Event produced by one of the sensor handlers
+#[derive(Debug, Clone)]
+pub struct SensorEvent {
+    pub device: String,
+    pub message: String,
+    pub critical: bool,
+}
+
+impl SensorEvent {
+    pub fn new(device: &str, message: impl Into<String>) -> Self {
+        Self {
+            device: device.to_owned(),
+            message: message.into(),
+            critical: false,
+        }
+    }
+
+    pub fn critical(mut self) -> Self {
+        self.critical = true;
+        self
+    }
+}
+
+/// Sends sensor notifications to discord
+///
+/// The channel depends on the house mode and quiet hours decide
+/// whether the message is sent right away
+pub struct Notifier {
+    outbound_queue: Arc<OutboundQueue>,
+    home: RwLock<HomeSettings>,
+    house_mode: Arc<HouseModeStore>,
+}
+
+impl Notifier {
+    pub fn new(
+        outbound_queue: Arc<OutboundQueue>,
+        home: HomeSettings,
+        house_mode: Arc<HouseModeStore>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            outbound_queue,
+            home: RwLock::new(home),
+            house_mode,
+        })
+    }
+
+    /// Apply reloaded device rules and quiet hours
+    pub fn update_settings(&self, home: HomeSettings) {
+        *self.home.write().expect("home settings lock poisoned") = home;
+    }
+
+    pub async fn notify(&self, mut event: SensorEvent) -> anyhow::Result<()> {
+        let mode = self.house_mode.get();
+        let now = Utc::now();
+        // decided up front because the settings lock can't be held across an await
+        let (channel, mentions, quiet_hours) = {
+            let home = self.home.read().expect("home settings lock poisoned");
+            let rule = home.device_rule(&event.device);
+            // device names come from wildcard topics so only configured ones get their own series
+            let device_label = rule.map_or(OTHER_DEVICES_LABEL, |rule| rule.name.as_str());
+            metrics()
+                .sensor_events
+                .with_label_values(&[device_label])
+                .inc();
+            let mut mentions = None;
+            let channel = if mode.is_away() || rule.map(|rule| rule.critical).unwrap_or(false) {
+                // nobody is home or the device is always important
+                event.critical = true;
+                mentions = Some(
+                    rule.and_then(|rule| rule.mentions.clone())
+                        .unwrap_or_else(|| home.default_mentions.clone()),
+                );
+                ChannelId(home.notification_discord_channel)
+            } else if mode == HouseMode::Night && event.critical {
+                ChannelId(home.notification_discord_channel)
+            } else {
+                ChannelId(home.spam_channel_id)
+            };
+            let quiet_hours = home
+                .quiet_hours
+                .iter()
+                .enumerate()
+                .find(|(_, quiet_hours)| {
+                    quiet_hours.is_active(now) && quiet_hours.applies_to(&event.device, channel.0)
+                })
+                .map(|(index, quiet_hours)| (index, quiet_hours.clone()));
+            (channel, mentions, quiet_hours)
+        };
+        let mentions = mentions.as_ref();
+
+        let (index, quiet_hours) = match quiet_hours {
+            Some((_, quiet_hours)) if event.critical && quiet_hours.allow_critical => {
+                info!("Critical event from {} during quiet hours", event.device);
+                return self.send(channel, &event.message, false, mentions).await;
+            }
+            Some(found) => found,
+            None => return self.send(channel, &event.message, false, mentions).await,
+        };
+
+        match quiet_hours.action {
+            QuietHoursAction::Suppress => {
+                info!("Suppressed event from {} during quiet hours", event.device);
+                Ok(())
+            }
+            QuietHoursAction::Digest => {
+                // stored with the outbound queue so restarts don't lose the digest
+                let local_time = now.with_timezone(&quiet_hours.timezone).format("%H:%M");
+                self.outbound_queue
+                    .add_digest_line(index, channel.0, format!("{local_time} {}", event.message))
+                    .await
+            }
+            QuietHoursAction::Silent => self.send(channel, &event.message, true, mentions).await,
+        }
+    }
+
+    /// Post digests for quiet hours that have ended
+    pub async fn flush_digests(&self) -> anyhow::Result<()> {
+        let now = Utc::now();
+        let pending = self.outbound_queue.pending_digests().await?;
+        let ended: Vec<_> = {
+            let home = self.home.read().expect("home settings lock poisoned");
+            // quiet hours removed by a reload or restart count as ended
+            pending
+                .into_iter()
+                .filter(|(index, _)| {
+                    home.quiet_hours
+                        .get(*index)
+                        .map(|quiet_hours| !quiet_hours.is_active(now))
+                        .unwrap_or(true)
+                })
+                .collect()
+        };
+
+        for (index, channel_id) in ended {
+            self.outbound_queue
+                .post_digest(index, channel_id, move |lines| {
+                    digest_messages(lines)
+                        .into_iter()
+                        .map(|content| OutboundMessage::new(ChannelId(channel_id), content))
+                        .collect()
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub fn start_digest_task(self: &Arc<Self>) {
+        let notifier = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DIGEST_FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = notifier.flush_digests().await {
+                    error!("Failed sending quiet hours digest {e}");
+                }
+            }
+        });
+    }
+
+    async fn send(
+        &self,
+        channel: ChannelId,
+        content: &str,
+        silent: bool,
+        mentions: Option<&MentionTargets>,
+    ) -> anyhow::Result<()> {
+        let mentions = mentions.cloned().unwrap_or_default();
+        let content = match mention_prefix(&mentions) {
+            Some(prefix) => format!("{prefix} {content}"),
+            None => content.to_owned(),
+        };
+        self.outbound_queue
+            .enqueue(OutboundMessage {
+                channel_id: channel.0,
+                content,
+                mentions,
+                silent,
+                origin: None,
+            })
+            .await
+    }
+}
+
+/// Digest text split into messages that fit discord's length limit
+fn digest_messages(lines: &[String]) -> Vec<String> {
+    let mut messages = vec![];
+    let mut message = format!("{} events during quiet hours:", lines.len());
+    for line in lines {
+        let mut entry = format!("- {line}");
+        truncate_to(&mut entry, MAX_MESSAGE_LENGTH);
+        if message.len() + 1 + entry.len() > MAX_MESSAGE_LENGTH {
+            messages.push(std::mem::take(&mut message));
+        }
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        message.push_str(&entry);
+    }
+    messages.push(message);
+    messages
+}
+
+/// Cut the text to at most max_len bytes on a char boundary
+fn truncate_to(text: &mut String, max_len: usize) {
+    if text.len() > max_len {
+        let mut end = max_len;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+}
+
+/// Text that pings the targets, None if there is nobody to ping
+pub fn mention_prefix(mentions: &MentionTargets) -> Option<String> {
+    let mut parts: Vec<_> = mentions
+        .users
+        .iter()
+        .map(|user| format!("<@{user}>"))
+        .chain(mentions.roles.iter().map(|role| format!("<@&{role}>")))
+        .collect();
+    if mentions.everyone {
+        parts.push("@everyone".to_owned());
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" "))
+    }
+}
+
+/// Restrict mentions to exactly the targets
+///
+/// Anything else in the message content won't ping anyone
+pub fn allow_only<'a>(
+    allowed: &'a mut CreateAllowedMentions,
+    mentions: &MentionTargets,
+) -> &'a mut CreateAllowedMentions {
+    allowed.empty_parse();
+    if mentions.everyone {
+        allowed.parse(ParseValue::Everyone);
+    }
+    allowed
+        .users(mentions.users.iter().copied().map(UserId))
+        .roles(mentions.roles.iter().copied().map(RoleId))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_digest_is_one_message() {
+        let lines = vec![
+            "23:10 door opened".to_owned(),
+            "23:20 door closed".to_owned(),
+        ];
+        assert_eq!(
+            digest_messages(&lines),
+            vec!["2 events during quiet hours:\n- 23:10 door opened\n- 23:20 door closed"]
+        );
+    }
+
+    #[test]
+    fn long_digest_is_split_under_the_limit() {
+        let lines: Vec<_> = (0..200)
+            .map(|i| format!("03:{:02} motion sensor detected motion", i % 60))
+            .collect();
+        let messages = digest_messages(&lines);
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert!(message.len() <= MAX_MESSAGE_LENGTH);
+            assert!(!message.starts_with('\n'));
+        }
+        let entries: usize = messages
+            .iter()
+            .map(|message| {
+                message
+                    .lines()
+                    .filter(|line| line.starts_with("- "))
+                    .count()
+            })
+            .sum();
+        assert_eq!(entries, lines.len());
+    }
+
+    #[test]
+    fn oversized_line_is_truncated() {
+        let lines = vec!["é".repeat(MAX_MESSAGE_LENGTH)];
+        let messages = digest_messages(&lines);
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.len() <= MAX_MESSAGE_LENGTH));
+    }
+}
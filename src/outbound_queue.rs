@@ -0,0 +1,872 @@
+use crate::{
+    configuration::{MentionTargets, OutboundQueueConfig, RateLimitPolicy},
+    metrics::{metrics, time_discord_request},
+    notifications::allow_only,
+};
+use anyhow::Context;
+use chrono::Utc;
+use log::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serenity::{builder::CreateMessage, http::Http, model::id::ChannelId};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, Notify};
+
+/// Discord's SUPPRESS_NOTIFICATIONS message flag
+/// serenity 0.11 doesn't know it and `MessageFlags::from_bits_truncate` drops it
+const SUPPRESS_NOTIFICATIONS_FLAG: u64 = 1 << 12;
+/// How long the worker sleeps when nothing is scheduled
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// Discord's limit on message length
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+const DROPPED_MESSAGES_CAPACITY: usize = 32;
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Message waiting to be sent to discord
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboundMessage {
+    pub channel_id: u64,
+    pub content: String,
+    /// Only these targets can be pinged by the content
+    #[serde(default)]
+    pub mentions: MentionTargets,
+    /// Send without a push notification
+    #[serde(default)]
+    pub silent: bool,
+    /// Reported with the error if the message can't be sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<MessageOrigin>,
+}
+
+/// MQTT message a queued message was created from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageOrigin {
+    pub topic: String,
+    pub handler: String,
+    pub request_id: Option<serde_json::Value>,
+}
+
+impl OutboundMessage {
+    pub fn new(channel: ChannelId, content: impl Into<String>) -> Self {
+        Self {
+            channel_id: channel.0,
+            content: content.into(),
+            mentions: MentionTargets::default(),
+            silent: false,
+            origin: None,
+        }
+    }
+
+    /// Messages can only be merged if they ping the same people the same way
+    /// and fail for the same request
+    fn can_merge(&self, other: &OutboundMessage) -> bool {
+        self.channel_id == other.channel_id
+            && self.mentions == other.mentions
+            && self.silent == other.silent
+            && self.origin == other.origin
+            && self.content.len() + other.content.len() < MAX_MESSAGE_LENGTH
+    }
+
+    async fn send(&self, discord: &Http) -> serenity::Result<()> {
+        ChannelId(self.channel_id)
+            .send_message(discord, |message| self.build(message))
+            .await?;
+        Ok(())
+    }
+
+    fn build<'a, 'b>(&self, message: &'b mut CreateMessage<'a>) -> &'b mut CreateMessage<'a> {
+        message
+            .content(&self.content)
+            .allowed_mentions(|allowed| allow_only(allowed, &self.mentions));
+        if self.silent {
+            // set the raw bit, the builder's flags go through MessageFlags
+            message.0.insert(
+                "flags",
+                serde_json::Value::from(SUPPRESS_NOTIFICATIONS_FLAG),
+            );
+        }
+        message
+    }
+}
+
+/// Why a message was removed from the queue without being sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    RateLimited,
+    DeadLetter,
+}
+
+/// Message the queue gave up on
+#[derive(Debug, Clone)]
+pub struct DroppedMessage {
+    pub reason: DropReason,
+    pub message: OutboundMessage,
+    pub error: String,
+}
+
+struct QueuedMessage {
+    id: i64,
+    created_at: i64,
+    attempts: u32,
+    message: OutboundMessage,
+}
+
+/// Persistent queue of messages to discord
+///
+/// Messages are stored in SQLite before sending so they survive restarts
+/// and discord outages. Failed sends are retried with exponential backoff
+/// until they get too old and are moved to the dead letter table.
+pub struct OutboundQueue {
+    connection: Arc<Mutex<Connection>>,
+    config: OutboundQueueConfig,
+    new_message: Notify,
+    rate_limiter: Mutex<RateLimiter>,
+    dropped: broadcast::Sender<DroppedMessage>,
+}
+
+impl OutboundQueue {
+    pub fn open(config: OutboundQueueConfig) -> anyhow::Result<Arc<Self>> {
+        // systemd creates the state directory but plain runs need it made
+        if let Some(directory) = config
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(directory).with_context(|| {
+                format!(
+                    "Failed creating outbound queue directory {directory:?}, \
+                    set outbound_queue.path to a writable location"
+                )
+            })?;
+        }
+        let connection = Connection::open(&config.path).with_context(|| {
+            format!(
+                "Failed opening outbound queue {:?}, set outbound_queue.path to a writable location",
+                config.path
+            )
+        })?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS outbound (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                created_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                payload TEXT NOT NULL,
+                last_error TEXT
+            );
+            CREATE TABLE IF NOT EXISTS dead_letter (
+                id INTEGER PRIMARY KEY,
+                created_at INTEGER NOT NULL,
+                failed_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL,
+                payload TEXT NOT NULL,
+                last_error TEXT
+            );
+            CREATE TABLE IF NOT EXISTS digest_lines (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                quiet_hours INTEGER NOT NULL,
+                channel_id INTEGER NOT NULL,
+                line TEXT NOT NULL
+            );",
+        )?;
+        let rate_limiter = RateLimiter::new(
+            config.rate_limit.per_channel_per_minute,
+            config.rate_limit.global_per_minute,
+        );
+        info!(
+            "Outbound queue has {} pending messages",
+            count_pending(&connection)?
+        );
+        let (dropped, _) = broadcast::channel(DROPPED_MESSAGES_CAPACITY);
+        Ok(Arc::new(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            config,
+            new_message: Notify::new(),
+            rate_limiter: Mutex::new(rate_limiter),
+            dropped,
+        }))
+    }
+
+    pub async fn enqueue(&self, message: OutboundMessage) -> anyhow::Result<()> {
+        self.with_connection(move |connection| insert_message(connection, &message))
+            .await?;
+        self.new_message.notify_one();
+        Ok(())
+    }
+
+    /// Keep a quiet hours digest line until the digest is posted
+    pub async fn add_digest_line(
+        &self,
+        quiet_hours: usize,
+        channel_id: u64,
+        line: String,
+    ) -> anyhow::Result<()> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO digest_lines (quiet_hours, channel_id, line) VALUES (?1, ?2, ?3)",
+                params![quiet_hours as i64, channel_id as i64, line],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Quiet hours index and channel of every digest with pending lines
+    pub async fn pending_digests(&self) -> anyhow::Result<Vec<(usize, u64)>> {
+        self.with_connection(|connection| {
+            let mut statement =
+                connection.prepare("SELECT DISTINCT quiet_hours, channel_id FROM digest_lines")?;
+            let digests = statement
+                .query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)? as usize, row.get::<_, i64>(1)? as u64))
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(digests)
+        })
+        .await
+    }
+
+    /// Replace the lines of a digest with the messages built from them
+    /// in one transaction so a crash neither loses nor repeats the digest
+    pub async fn post_digest(
+        &self,
+        quiet_hours: usize,
+        channel_id: u64,
+        build_messages: impl FnOnce(&[String]) -> Vec<OutboundMessage> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            let lines: Vec<String> = transaction
+                .prepare(
+                    "SELECT line FROM digest_lines WHERE quiet_hours = ?1 AND channel_id = ?2
+                    ORDER BY id",
+                )?
+                .query_map(params![quiet_hours as i64, channel_id as i64], |row| {
+                    row.get(0)
+                })?
+                .collect::<Result<_, _>>()?;
+            for message in build_messages(&lines) {
+                insert_message(&transaction, &message)?;
+            }
+            transaction.execute(
+                "DELETE FROM digest_lines WHERE quiet_hours = ?1 AND channel_id = ?2",
+                params![quiet_hours as i64, channel_id as i64],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        self.new_message.notify_one();
+        Ok(())
+    }
+
+    pub async fn len(&self) -> anyhow::Result<usize> {
+        self.with_connection(|connection| count_pending(connection))
+            .await
+    }
+
+    /// Wait for pending messages to be sent, returns how many are left when we give up
+    pub async fn drain(&self, timeout: Duration) -> anyhow::Result<usize> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let pending = self.len().await?;
+            if pending == 0 || tokio::time::Instant::now() >= deadline {
+                return Ok(pending);
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Messages that were dropped or dead lettered
+    pub fn subscribe_dropped(&self) -> broadcast::Receiver<DroppedMessage> {
+        self.dropped.subscribe()
+    }
+
+    pub fn start(self: &Arc<Self>, discord: Arc<Http>) {
+        let queue = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = queue.process_next(&discord).await {
+                    error!("Outbound queue failed {e:?}");
+                    tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                }
+            }
+        });
+    }
+
+    /// SQLite calls block so they run on the blocking pool instead of a tokio worker
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            query(&mut connection.lock().expect("outbound queue lock poisoned"))
+        })
+        .await?
+    }
+
+    /// Send the next due message or wait until one is due
+    async fn process_next(&self, discord: &Http) -> anyhow::Result<()> {
+        let now = Utc::now().timestamp_millis();
+        let queued = match self.next_due(now).await? {
+            Some(queued) => queued,
+            None => {
+                let wait = self
+                    .next_attempt_at()
+                    .await?
+                    .map(|next| Duration::from_millis((next - now).max(0) as u64))
+                    .unwrap_or(IDLE_POLL_INTERVAL)
+                    .min(IDLE_POLL_INTERVAL);
+                // wake up early if something new arrives
+                let _ = tokio::time::timeout(wait, self.new_message.notified()).await;
+                return Ok(());
+            }
+        };
+
+        let rate_limit_wait = self
+            .rate_limiter
+            .lock()
+            .expect("rate limiter lock poisoned")
+            .check(queued.message.channel_id, Instant::now());
+        if let Some(wait) = rate_limit_wait {
+            metrics()
+                .rate_limit_hits
+                .with_label_values(&["outbound_queue"])
+                .inc();
+            let next_attempt_at = now + wait.as_millis() as i64;
+            return match self.config.rate_limit.policy {
+                RateLimitPolicy::Drop => {
+                    warn!(
+                        "Dropping message to {} over rate limit",
+                        queued.message.channel_id
+                    );
+                    self.remove(queued.id).await?;
+                    self.report_dropped(DropReason::RateLimited, queued.message, "Rate limited");
+                    Ok(())
+                }
+                RateLimitPolicy::Delay => self.delay(queued.id, next_attempt_at).await,
+                RateLimitPolicy::Coalesce => {
+                    self.coalesce(&queued).await?;
+                    self.delay(queued.id, next_attempt_at).await
+                }
+            };
+        }
+
+        self.rate_limiter
+            .lock()
+            .expect("rate limiter lock poisoned")
+            .record(queued.message.channel_id, Instant::now());
+        match time_discord_request("send_message", queued.message.send(discord)).await {
+            Ok(()) => self.remove(queued.id).await,
+            Err(e) => {
+                if is_rate_limited(&e) {
+                    metrics()
+                        .rate_limit_hits
+                        .with_label_values(&["discord"])
+                        .inc();
+                }
+                let attempts = queued.attempts + 1;
+                let age = Duration::from_millis((now - queued.created_at).max(0) as u64);
+                if !is_retryable(&e) || age > Duration::from_secs(self.config.max_age_secs) {
+                    error!(
+                        "Giving up on message to {} after {attempts} attempts {e}",
+                        queued.message.channel_id
+                    );
+                    self.dead_letter(&queued, attempts, &e.to_string()).await?;
+                    self.report_dropped(DropReason::DeadLetter, queued.message, &e.to_string());
+                    Ok(())
+                } else {
+                    let backoff = self.backoff(attempts);
+                    warn!(
+                        "Failed sending message to {} retrying in {:?} {e}",
+                        queued.message.channel_id, backoff
+                    );
+                    self.reschedule(queued.id, attempts, now + backoff.as_millis() as i64, &e)
+                        .await
+                }
+            }
+        }
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let initial = self.config.initial_backoff_secs.max(1);
+        let backoff = initial.saturating_mul(2_u64.saturating_pow(attempts.saturating_sub(1)));
+        Duration::from_secs(backoff.min(self.config.max_backoff_secs))
+    }
+
+    async fn next_due(&self, now: i64) -> anyhow::Result<Option<QueuedMessage>> {
+        self.with_connection(move |connection| {
+            let row = connection
+                .query_row(
+                    "SELECT id, created_at, attempts, payload FROM outbound
+                    WHERE next_attempt_at <= ?1 ORDER BY id LIMIT 1",
+                    params![now],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, u32>(2)?,
+                            row.get::<_, String>(3)?,
+                        ))
+                    },
+                )
+                .optional()?;
+            let Some((id, created_at, attempts, payload)) = row else {
+                return Ok(None);
+            };
+            match serde_json::from_str(&payload) {
+                Ok(message) => Ok(Some(QueuedMessage {
+                    id,
+                    created_at,
+                    attempts,
+                    message,
+                })),
+                Err(e) => {
+                    error!("Dropping unreadable queued message {id} {e}");
+                    connection.execute("DELETE FROM outbound WHERE id = ?1", params![id])?;
+                    Ok(None)
+                }
+            }
+        })
+        .await
+    }
+
+    async fn next_attempt_at(&self) -> anyhow::Result<Option<i64>> {
+        self.with_connection(|connection| {
+            let next =
+                connection.query_row("SELECT MIN(next_attempt_at) FROM outbound", [], |row| {
+                    row.get(0)
+                })?;
+            Ok(next)
+        })
+        .await
+    }
+
+    async fn remove(&self, id: i64) -> anyhow::Result<()> {
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM outbound WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Postpone a message without counting it as a failed attempt
+    async fn delay(&self, id: i64, next_attempt_at: i64) -> anyhow::Result<()> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE outbound SET next_attempt_at = ?2 WHERE id = ?1",
+                params![id, next_attempt_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Merge later messages for the same channel into the queued message
+    async fn coalesce(&self, queued: &QueuedMessage) -> anyhow::Result<()> {
+        let id = queued.id;
+        let message = queued.message.clone();
+        self.with_connection(move |connection| coalesce_into(connection, id, message))
+            .await
+    }
+
+    /// Take a rate limit slot for a discord request that doesn't go through the queue
+    ///
+    /// Typing and files can't wait in the queue so over the limit they are dropped
+    pub fn acquire_direct(&self, message: OutboundMessage) -> bool {
+        let now = Instant::now();
+        {
+            let mut rate_limiter = self
+                .rate_limiter
+                .lock()
+                .expect("rate limiter lock poisoned");
+            if rate_limiter.check(message.channel_id, now).is_none() {
+                rate_limiter.record(message.channel_id, now);
+                return true;
+            }
+        }
+        metrics()
+            .rate_limit_hits
+            .with_label_values(&["outbound_queue"])
+            .inc();
+        warn!(
+            "Dropping direct request to {} over rate limit",
+            message.channel_id
+        );
+        self.report_dropped(DropReason::RateLimited, message, "Rate limited");
+        false
+    }
+
+    fn report_dropped(&self, reason: DropReason, message: OutboundMessage, error: &str) {
+        // nobody listening is fine
+        let _ = self.dropped.send(DroppedMessage {
+            reason,
+            message,
+            error: error.to_owned(),
+        });
+    }
+
+    async fn reschedule(
+        &self,
+        id: i64,
+        attempts: u32,
+        next_attempt_at: i64,
+        error: &serenity::Error,
+    ) -> anyhow::Result<()> {
+        let error = error.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE outbound SET attempts = ?2, next_attempt_at = ?3, last_error = ?4
+                WHERE id = ?1",
+                params![id, attempts, next_attempt_at, error],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn dead_letter(
+        &self,
+        queued: &QueuedMessage,
+        attempts: u32,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        let payload = serde_json::to_string(&queued.message)?;
+        let (id, created_at, error) = (queued.id, queued.created_at, error.to_owned());
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO dead_letter (id, created_at, failed_at, attempts, payload, last_error)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    id,
+                    created_at,
+                    Utc::now().timestamp_millis(),
+                    attempts,
+                    payload,
+                    error
+                ],
+            )?;
+            transaction.execute("DELETE FROM outbound WHERE id = ?1", params![id])?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn count_pending(connection: &Connection) -> anyhow::Result<usize> {
+    let count: i64 = connection.query_row("SELECT COUNT(*) FROM outbound", [], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+/// Append later messages for the same channel until one can't be merged
+fn coalesce_into(
+    connection: &mut Connection,
+    queued_id: i64,
+    mut merged: OutboundMessage,
+) -> anyhow::Result<()> {
+    let transaction = connection.transaction()?;
+    let later: Vec<(i64, String)> = transaction
+        .prepare("SELECT id, payload FROM outbound WHERE id > ?1 ORDER BY id")?
+        .query_map(params![queued_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    let mut merged_ids = vec![];
+    for (id, payload) in later {
+        let Ok(message) = serde_json::from_str::<OutboundMessage>(&payload) else {
+            continue;
+        };
+        if message.channel_id != merged.channel_id {
+            continue;
+        }
+        // stop at the first message that has to be sent separately to keep the order
+        if !merged.can_merge(&message) {
+            break;
+        }
+        merged.content.push('\n');
+        merged.content.push_str(&message.content);
+        merged_ids.push(id);
+    }
+    if merged_ids.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Coalesced {} messages to {}",
+        merged_ids.len(),
+        merged.channel_id
+    );
+    transaction.execute(
+        "UPDATE outbound SET payload = ?2 WHERE id = ?1",
+        params![queued_id, serde_json::to_string(&merged)?],
+    )?;
+    for id in merged_ids {
+        transaction.execute("DELETE FROM outbound WHERE id = ?1", params![id])?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn insert_message(connection: &Connection, message: &OutboundMessage) -> anyhow::Result<()> {
+    let payload = serde_json::to_string(message)?;
+    let now = Utc::now().timestamp_millis();
+    connection.execute(
+        "INSERT INTO outbound (created_at, next_attempt_at, payload) VALUES (?1, ?1, ?2)",
+        params![now, payload],
+    )?;
+    Ok(())
+}
+
+/// Sliding window of recently sent messages
+struct RateLimiter {
+    per_channel: usize,
+    global: usize,
+    sent: VecDeque<(Instant, u64)>,
+}
+
+impl RateLimiter {
+    fn new(per_channel: usize, global: usize) -> Self {
+        Self {
+            per_channel,
+            global,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// How long until a message to the channel can be sent, None if it can go now
+    fn check(&mut self, channel_id: u64, now: Instant) -> Option<Duration> {
+        while let Some((sent_at, _)) = self.sent.front() {
+            if now.duration_since(*sent_at) < RATE_LIMIT_WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        let free_at = |sent_at: Instant| RATE_LIMIT_WINDOW.saturating_sub(now - sent_at);
+
+        if self.global > 0 && self.sent.len() >= self.global {
+            return self.sent.front().map(|(sent_at, _)| free_at(*sent_at));
+        }
+        if self.per_channel > 0 {
+            let mut channel_sent = self.sent.iter().filter(|(_, id)| *id == channel_id);
+            if channel_sent.clone().count() >= self.per_channel {
+                return channel_sent.next().map(|(sent_at, _)| free_at(*sent_at));
+            }
+        }
+        None
+    }
+
+    fn record(&mut self, channel_id: u64, now: Instant) {
+        self.sent.push_back((now, channel_id));
+    }
+}
+
+fn is_rate_limited(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(http_error) => http_error
+            .status_code()
+            .map(|status| status.as_u16() == 429)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Discord outages, rate limits and network errors are worth retrying
+/// but a 4xx like a missing channel will never succeed
+fn is_retryable(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(http_error) => match http_error.status_code() {
+            Some(status) => status.is_server_error() || status.as_u16() == 429,
+            None => true,
+        },
+        serenity::Error::Model(_) | serenity::Error::Json(_) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn open_queue(dir: &TempDir) -> Arc<OutboundQueue> {
+        OutboundQueue::open(OutboundQueueConfig {
+            path: dir.path().join("queue.sqlite"),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn digest_lines_survive_reopening_the_queue() {
+        let dir = TempDir::new("outbound_queue").unwrap();
+        {
+            let queue = open_queue(&dir);
+            for (quiet_hours, channel_id, line) in [
+                (0, 10, "23:10 door opened"),
+                (0, 10, "23:20 door closed"),
+                (1, 20, "01:00 motion"),
+            ] {
+                queue
+                    .add_digest_line(quiet_hours, channel_id, line.to_owned())
+                    .await
+                    .unwrap();
+            }
+        }
+        let queue = open_queue(&dir);
+        let mut pending = queue.pending_digests().await.unwrap();
+        pending.sort();
+        assert_eq!(pending, vec![(0, 10), (1, 20)]);
+
+        queue
+            .post_digest(0, 10, |lines| {
+                assert_eq!(lines, ["23:10 door opened", "23:20 door closed"]);
+                vec![OutboundMessage::new(ChannelId(10), lines.join("\n"))]
+            })
+            .await
+            .unwrap();
+        assert_eq!(queue.pending_digests().await.unwrap(), vec![(1, 20)]);
+        assert_eq!(queue.len().await.unwrap(), 1);
+    }
+
+    #[test]
+    fn silent_message_sets_suppress_notifications_flag() {
+        let mut message = OutboundMessage::new(ChannelId(1), "hello");
+        message.silent = true;
+        let mut create = CreateMessage::default();
+        message.build(&mut create);
+        assert_eq!(create.0.get("flags"), Some(&serde_json::json!(4096)));
+    }
+
+    #[test]
+    fn normal_message_has_no_flags() {
+        let message = OutboundMessage::new(ChannelId(1), "hello");
+        let mut create = CreateMessage::default();
+        message.build(&mut create);
+        assert_eq!(create.0.get("flags"), None);
+    }
+
+    async fn queued_contents(queue: &OutboundQueue) -> Vec<String> {
+        queue
+            .with_connection(|connection| {
+                let contents = connection
+                    .prepare("SELECT payload FROM outbound ORDER BY id")?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .map(|payload| {
+                        let message: OutboundMessage = serde_json::from_str(&payload?)?;
+                        Ok(message.content)
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(contents)
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn coalesce_first(queue: &OutboundQueue) {
+        let queued = queue.next_due(i64::MAX).await.unwrap().unwrap();
+        queue.coalesce(&queued).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn coalesce_merges_messages_to_the_same_channel() {
+        let dir = TempDir::new("outbound_queue").unwrap();
+        let queue = open_queue(&dir);
+        for (channel, content) in [(1, "first"), (2, "other channel"), (1, "second")] {
+            queue
+                .enqueue(OutboundMessage::new(ChannelId(channel), content))
+                .await
+                .unwrap();
+        }
+        coalesce_first(&queue).await;
+        assert_eq!(
+            queued_contents(&queue).await,
+            vec!["first\nsecond", "other channel"]
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesce_stops_at_a_message_with_different_mentions_or_silence() {
+        let dir = TempDir::new("outbound_queue").unwrap();
+        let queue = open_queue(&dir);
+        let mut pinged = OutboundMessage::new(ChannelId(1), "pinged");
+        pinged.mentions.users.push(42);
+        let mut silent = OutboundMessage::new(ChannelId(1), "silent");
+        silent.silent = true;
+        for message in [
+            OutboundMessage::new(ChannelId(1), "first"),
+            pinged,
+            silent,
+            OutboundMessage::new(ChannelId(1), "last"),
+        ] {
+            queue.enqueue(message).await.unwrap();
+        }
+        coalesce_first(&queue).await;
+        assert_eq!(
+            queued_contents(&queue).await,
+            vec!["first", "pinged", "silent", "last"]
+        );
+    }
+
+    #[test]
+    fn messages_from_different_requests_are_not_merged() {
+        let origin = |request_id| MessageOrigin {
+            topic: "discord/send".to_owned(),
+            handler: "DiscordChannelMessageHandler".to_owned(),
+            request_id: Some(serde_json::json!(request_id)),
+        };
+        let mut first = OutboundMessage::new(ChannelId(1), "first");
+        first.origin = Some(origin(1));
+        let mut second = OutboundMessage::new(ChannelId(1), "second");
+        second.origin = Some(origin(2));
+        assert!(!first.can_merge(&second));
+        second.origin = Some(origin(1));
+        assert!(first.can_merge(&second));
+    }
+
+    #[test]
+    fn merged_message_stays_under_the_length_limit() {
+        let first = OutboundMessage::new(ChannelId(1), "a".repeat(MAX_MESSAGE_LENGTH / 2));
+        let second = OutboundMessage::new(ChannelId(1), "b".repeat(MAX_MESSAGE_LENGTH / 2));
+        assert!(!first.can_merge(&second));
+        let shorter = OutboundMessage::new(ChannelId(1), "b".repeat(MAX_MESSAGE_LENGTH / 2 - 1));
+        assert!(first.can_merge(&shorter));
+    }
+
+    #[test]
+    fn rate_limiter_limits_each_channel() {
+        let mut limiter = RateLimiter::new(2, 0);
+        let start = Instant::now();
+        limiter.record(1, start);
+        limiter.record(1, start + Duration::from_secs(10));
+        assert_eq!(
+            limiter.check(1, start + Duration::from_secs(20)),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(limiter.check(2, start + Duration::from_secs(20)), None);
+    }
+
+    #[test]
+    fn rate_limiter_limits_all_channels() {
+        let mut limiter = RateLimiter::new(0, 2);
+        let start = Instant::now();
+        limiter.record(1, start);
+        limiter.record(2, start);
+        assert_eq!(limiter.check(3, start), Some(RATE_LIMIT_WINDOW));
+    }
+
+    #[test]
+    fn rate_limiter_forgets_sends_outside_the_window() {
+        let mut limiter = RateLimiter::new(1, 1);
+        let start = Instant::now();
+        limiter.record(1, start);
+        assert!(limiter.check(1, start + RATE_LIMIT_WINDOW).is_none());
+        assert!(limiter.sent.is_empty());
+    }
+}
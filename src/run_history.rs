@@ -0,0 +1,61 @@
+use crate::house_mode::write_atomically;
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, time::Duration};
+
+/// How often we record that we are still running
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct RunRecord {
+    started_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Start recording this run and return how long the previous run was up
+pub fn start_run(run_history_file: PathBuf) -> Option<chrono::Duration> {
+    let previous_uptime = match std::fs::read_to_string(&run_history_file) {
+        Ok(data) => match serde_json::from_str::<RunRecord>(&data) {
+            Ok(record) => Some(record.last_seen - record.started_at),
+            Err(e) => {
+                error!("Failed parsing run history {:?} {e}", run_history_file);
+                None
+            }
+        },
+        Err(e) => {
+            warn!("Failed reading run history {:?} {e}", run_history_file);
+            None
+        }
+    };
+
+    let started_at = Utc::now();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let record = RunRecord {
+                started_at,
+                last_seen: Utc::now(),
+            };
+            let result = serde_json::to_string(&record)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(write_atomically(&run_history_file, json.as_bytes())?));
+            if let Err(e) = result {
+                error!("Failed writing run history {:?} {e}", run_history_file);
+            }
+        }
+    });
+    previous_uptime
+}
+
+/// Short human readable duration such as "2d 3h 15m"
+pub fn format_uptime(uptime: chrono::Duration) -> String {
+    let minutes = uptime.num_minutes();
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
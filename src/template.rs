@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+/// Replace `{name}` placeholders with their values
+/// Unknown placeholders are left as they are so typos show up in the output
+pub fn render(template: &str, values: &HashMap<&str, String>) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        match placeholder
+            .find('}')
+            .and_then(|end| values.get(&placeholder[1..end]).map(|value| (end, value)))
+        {
+            Some((end, value)) => {
+                output.push_str(value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Check that every placeholder is closed and one of the known names
+pub fn validate(template: &str, known: &[&str]) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let placeholder = &rest[start + 1..];
+        let end = placeholder
+            .find('}')
+            .ok_or_else(|| format!("unclosed placeholder in {template:?}"))?;
+        let name = &placeholder[..end];
+        if !known.contains(&name) {
+            return Err(format!(
+                "unknown placeholder {{{name}}} in {template:?}, expected one of {known:?}"
+            ));
+        }
+        rest = &placeholder[end + 1..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> HashMap<&'static str, String> {
+        HashMap::from([("uptime", "2h 5m".to_owned())])
+    }
+
+    #[test]
+    fn render_replaces_known_placeholders() {
+        assert_eq!(
+            render("Back after {uptime} and {uptime}", &values()),
+            "Back after 2h 5m and 2h 5m"
+        );
+    }
+
+    #[test]
+    fn render_keeps_unknown_and_unclosed_placeholders() {
+        assert_eq!(
+            render("{typo} {uptime} {uptime", &values()),
+            "{typo} 2h 5m {uptime"
+        );
+    }
+
+    #[test]
+    fn validate_accepts_known_placeholders() {
+        assert!(validate("Up for {uptime}", &["uptime"]).is_ok());
+        assert!(validate("No placeholders", &["uptime"]).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_unknown_and_unclosed_placeholders() {
+        assert!(validate("Up for {uptim}", &["uptime"])
+            .unwrap_err()
+            .contains("unknown placeholder {uptim}"));
+        assert!(validate("Up for {uptime", &["uptime"])
+            .unwrap_err()
+            .contains("unclosed placeholder"));
+    }
+}